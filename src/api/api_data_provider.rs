@@ -1,5 +1,20 @@
-use crate::api::client::ApiClient;
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+use crate::{api::client::ApiClient, batch::token_counter::TokenCounter, error::ProxyResult};
+
+use super::retry::RetryPolicy;
 
 pub struct ApiDataProvider<TApiClient: ApiClient> {
     pub api_client: TApiClient,
+    pub retry_policy: RetryPolicy,
+    pub availability: watch::Receiver<bool>,
+}
+
+/// Counts tokens with the upstream tokenizer.
+#[async_trait]
+impl<TApiClient: ApiClient> TokenCounter for ApiDataProvider<TApiClient> {
+    async fn count_tokens(&self, texts: &[&str]) -> ProxyResult<Vec<usize>> {
+        Ok(self.api_client.tokenize(texts).await?)
+    }
 }
@@ -1,22 +1,89 @@
+pub mod circuit_breaker_api_client;
+pub mod load_balanced_api_client;
 pub mod reqwest_api_client;
 
+use std::time::Duration;
+
 use async_trait::async_trait;
+use reqwest::StatusCode;
 use thiserror::Error;
 
-use super::endpoint::embed_endpoint::EmbedApiRequest;
+use super::endpoint::{
+    embed_endpoint::EmbedApiRequest,
+    embed_sparse_endpoint::{EmbedSparseApiRequest, SparseValue},
+    predict_endpoint::{PredictApiRequest, Prediction},
+    rerank_endpoint::{RerankApiRequest, RerankApiResponseItem},
+};
 
 pub type ApiClientResult<T> = Result<T, ApiClientError>;
 
 #[derive(Error, Debug)]
 pub enum ApiClientError {
+    #[error("Upstream responded with {status}: {message}")]
+    Upstream {
+        status: StatusCode,
+        message: String,
+        error_type: Option<String>,
+        retry_after: Option<Duration>,
+    },
+
+    #[error("Request timed out: {0:?}")]
+    Timeout(reqwest::Error),
+
+    #[error("Could not connect: {0:?}")]
+    Connect(reqwest::Error),
+
     #[error("Request failed: {0:?}")]
-    Request(#[from] reqwest::Error),
+    Request(reqwest::Error),
+
+    #[error("Circuit breaker is open.")]
+    CircuitOpen,
 
     #[error("Other error: {0:?}")]
     Other(#[from] anyhow::Error),
 }
 
+impl ApiClientError {
+    /// Whether the error means the upstream itself is failing, rather than rejecting the request.
+    pub fn is_upstream_failure(&self) -> bool {
+        match self {
+            ApiClientError::Upstream { status, .. } => status.is_server_error(),
+            ApiClientError::Timeout(_)
+            | ApiClientError::Connect(_)
+            | ApiClientError::Request(_) => true,
+            ApiClientError::CircuitOpen | ApiClientError::Other(_) => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ApiClientError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            ApiClientError::Timeout(error)
+        } else if error.is_connect() {
+            ApiClientError::Connect(error)
+        } else {
+            ApiClientError::Request(error)
+        }
+    }
+}
+
 #[async_trait]
 pub trait ApiClient: Send + Sync + 'static {
+    async fn check_health(&self) -> ApiClientResult<()>;
+    /// Token counts of the texts, in the same order.
+    async fn tokenize(&self, texts: &[&str]) -> ApiClientResult<Vec<usize>>;
     async fn call_embed(&self, request: &EmbedApiRequest) -> ApiClientResult<Vec<Vec<f64>>>;
+    async fn call_embed_sparse(
+        &self,
+        request: &EmbedSparseApiRequest,
+    ) -> ApiClientResult<Vec<Vec<SparseValue>>>;
+    async fn call_predict(
+        &self,
+        request: &PredictApiRequest,
+    ) -> ApiClientResult<Vec<Vec<Prediction>>>;
+    async fn call_rerank(
+        &self,
+        request: &RerankApiRequest,
+    ) -> ApiClientResult<Vec<RerankApiResponseItem>>;
 }
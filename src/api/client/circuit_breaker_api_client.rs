@@ -0,0 +1,334 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use metrics::{counter, gauge};
+use tokio::sync::watch;
+
+use crate::{
+    api::endpoint::{
+        embed_endpoint::EmbedApiRequest,
+        embed_sparse_endpoint::{EmbedSparseApiRequest, SparseValue},
+        predict_endpoint::{PredictApiRequest, Prediction},
+        rerank_endpoint::{RerankApiRequest, RerankApiResponseItem},
+    },
+    settings::CircuitBreakerSettings,
+};
+
+use super::{ApiClient, ApiClientError, ApiClientResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen { is_probing: bool },
+}
+
+impl CircuitState {
+    fn name(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen { .. } => "half_open",
+        }
+    }
+
+    fn metric_value(&self) -> f64 {
+        match self {
+            CircuitState::Closed => 0.0,
+            CircuitState::Open => 1.0,
+            CircuitState::HalfOpen { .. } => 2.0,
+        }
+    }
+}
+
+struct Circuit {
+    state: CircuitState,
+    /// Outcomes of the most recent calls, `true` for failures.
+    outcomes: VecDeque<bool>,
+}
+
+/// Fails calls immediately while the upstream is considered down. The circuit opens once
+/// the failure rate of recent calls reaches the threshold, and after the open duration
+/// lets a single probe call through to check whether the upstream has recovered.
+pub struct CircuitBreakerApiClient<TApiClient> {
+    inner: TApiClient,
+    settings: CircuitBreakerSettings,
+    circuit: Arc<Mutex<Circuit>>,
+    availability: Arc<watch::Sender<bool>>,
+}
+
+/// Releases the half-open probe slot if the probe call was cancelled before completing.
+struct ProbeGuard<'a> {
+    circuit: &'a Mutex<Circuit>,
+    is_probe: bool,
+}
+
+impl Drop for ProbeGuard<'_> {
+    fn drop(&mut self) {
+        if !self.is_probe {
+            return;
+        }
+
+        let mut circuit = self.circuit.lock().unwrap();
+        if let CircuitState::HalfOpen { is_probing } = &mut circuit.state {
+            *is_probing = false;
+        }
+    }
+}
+
+impl<TApiClient> CircuitBreakerApiClient<TApiClient> {
+    pub fn new(inner: TApiClient, settings: CircuitBreakerSettings) -> Self {
+        let (availability, _) = watch::channel(true);
+        gauge!("batch_proxy_circuit_breaker_state").set(CircuitState::Closed.metric_value());
+
+        Self {
+            inner,
+            settings,
+            circuit: Arc::new(Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                outcomes: VecDeque::new(),
+            })),
+            availability: Arc::new(availability),
+        }
+    }
+
+    pub fn inner(&self) -> &TApiClient {
+        &self.inner
+    }
+
+    /// Receives `false` while the circuit is open.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.availability.subscribe()
+    }
+
+    fn transition(circuit: &mut Circuit, state: CircuitState, availability: &watch::Sender<bool>) {
+        let previous = circuit.state;
+        circuit.state = state;
+        circuit.outcomes.clear();
+
+        match state {
+            CircuitState::Open => warn!(
+                "Circuit breaker opened, failing upstream calls. [previous_state = {}]",
+                previous.name()
+            ),
+            _ => info!(
+                "Circuit breaker state changed. [previous_state = {}, state = {}]",
+                previous.name(),
+                state.name()
+            ),
+        }
+
+        gauge!("batch_proxy_circuit_breaker_state").set(state.metric_value());
+        counter!("batch_proxy_circuit_breaker_transitions_total", "state" => state.name())
+            .increment(1);
+        availability.send_replace(state != CircuitState::Open);
+    }
+
+    fn open(&self, circuit: &mut Circuit) {
+        Self::transition(circuit, CircuitState::Open, &self.availability);
+
+        let circuit = Arc::clone(&self.circuit);
+        let availability = Arc::clone(&self.availability);
+        let open_duration = Duration::from_millis(self.settings.open_duration_ms);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(open_duration).await;
+
+            let mut circuit = circuit.lock().unwrap();
+            if circuit.state == CircuitState::Open {
+                Self::transition(
+                    &mut circuit,
+                    CircuitState::HalfOpen { is_probing: false },
+                    &availability,
+                );
+            }
+        });
+    }
+
+    /// Checks whether the call is allowed, returns whether it is the half-open probe.
+    fn try_acquire(&self) -> ApiClientResult<bool> {
+        let mut circuit = self.circuit.lock().unwrap();
+
+        match &mut circuit.state {
+            CircuitState::Closed => Ok(false),
+            CircuitState::HalfOpen { is_probing } if !*is_probing => {
+                *is_probing = true;
+                Ok(true)
+            }
+            CircuitState::Open | CircuitState::HalfOpen { .. } => {
+                counter!("batch_proxy_circuit_breaker_rejected_calls_total").increment(1);
+                Err(ApiClientError::CircuitOpen)
+            }
+        }
+    }
+
+    fn record(&self, is_failure: bool) {
+        let mut circuit = self.circuit.lock().unwrap();
+
+        match circuit.state {
+            CircuitState::HalfOpen { .. } if is_failure => self.open(&mut circuit),
+            CircuitState::HalfOpen { .. } => {
+                Self::transition(&mut circuit, CircuitState::Closed, &self.availability)
+            }
+            CircuitState::Closed => {
+                circuit.outcomes.push_back(is_failure);
+                if circuit.outcomes.len() > self.settings.window_size {
+                    circuit.outcomes.pop_front();
+                }
+
+                let calls = circuit.outcomes.len();
+                let failures = circuit.outcomes.iter().filter(|f| **f).count();
+                if calls >= self.settings.minimum_calls
+                    && failures as f64 / calls as f64 >= self.settings.failure_rate_threshold
+                {
+                    self.open(&mut circuit);
+                }
+            }
+            // Calls that started before the circuit opened.
+            CircuitState::Open => {}
+        }
+    }
+
+    async fn call<'a, TResponse, TFuture>(
+        &'a self,
+        call: impl FnOnce(&'a TApiClient) -> TFuture,
+    ) -> ApiClientResult<TResponse>
+    where
+        TFuture: Future<Output = ApiClientResult<TResponse>>,
+    {
+        let is_probe = self.try_acquire()?;
+        let mut probe_guard = ProbeGuard {
+            circuit: &self.circuit,
+            is_probe,
+        };
+
+        let result = call(&self.inner).await;
+
+        probe_guard.is_probe = false;
+        self.record(result.as_ref().is_err_and(|e| e.is_upstream_failure()));
+
+        result
+    }
+}
+
+#[async_trait]
+impl<TApiClient: ApiClient> ApiClient for CircuitBreakerApiClient<TApiClient> {
+    async fn check_health(&self) -> ApiClientResult<()> {
+        self.inner.check_health().await
+    }
+
+    async fn tokenize(&self, texts: &[&str]) -> ApiClientResult<Vec<usize>> {
+        self.call(|client| client.tokenize(texts)).await
+    }
+
+    async fn call_embed(&self, request: &EmbedApiRequest) -> ApiClientResult<Vec<Vec<f64>>> {
+        self.call(|client| client.call_embed(request)).await
+    }
+
+    async fn call_embed_sparse(
+        &self,
+        request: &EmbedSparseApiRequest,
+    ) -> ApiClientResult<Vec<Vec<SparseValue>>> {
+        self.call(|client| client.call_embed_sparse(request)).await
+    }
+
+    async fn call_predict(
+        &self,
+        request: &PredictApiRequest,
+    ) -> ApiClientResult<Vec<Vec<Prediction>>> {
+        self.call(|client| client.call_predict(request)).await
+    }
+
+    async fn call_rerank(
+        &self,
+        request: &RerankApiRequest,
+    ) -> ApiClientResult<Vec<RerankApiResponseItem>> {
+        self.call(|client| client.call_rerank(request)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+
+    fn breaker() -> CircuitBreakerApiClient<()> {
+        CircuitBreakerApiClient::new(
+            (),
+            CircuitBreakerSettings {
+                window_size: 4,
+                minimum_calls: 4,
+                failure_rate_threshold: 0.5,
+                open_duration_ms: 100,
+            },
+        )
+    }
+
+    async fn call(breaker: &CircuitBreakerApiClient<()>, fail: bool) -> ApiClientResult<()> {
+        breaker
+            .call(|_| async move {
+                match fail {
+                    true => Err(ApiClientError::Upstream {
+                        status: StatusCode::INTERNAL_SERVER_ERROR,
+                        message: "error".to_string(),
+                        error_type: None,
+                        retry_after: None,
+                    }),
+                    false => Ok(()),
+                }
+            })
+            .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_failure_rate_reached_when_called_should_fail_immediately() {
+        let breaker = breaker();
+        let availability = breaker.subscribe();
+
+        for fail in [false, true, false, true] {
+            let _ = call(&breaker, fail).await;
+        }
+
+        assert!(matches!(
+            call(&breaker, false).await,
+            Err(ApiClientError::CircuitOpen)
+        ));
+        assert!(!*availability.borrow());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_open_circuit_when_probe_succeeds_should_close() {
+        let breaker = breaker();
+        for _ in 0..4 {
+            let _ = call(&breaker, true).await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(*breaker.subscribe().borrow());
+
+        assert!(call(&breaker, false).await.is_ok());
+        assert!(call(&breaker, false).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_half_open_circuit_when_probe_fails_should_open_again() {
+        let breaker = breaker();
+        for _ in 0..4 {
+            let _ = call(&breaker, true).await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let _ = call(&breaker, true).await;
+
+        assert!(matches!(
+            call(&breaker, false).await,
+            Err(ApiClientError::CircuitOpen)
+        ));
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::{
+    api::endpoint::{
+        embed_endpoint::EmbedApiRequest,
+        embed_sparse_endpoint::{EmbedSparseApiRequest, SparseValue},
+        predict_endpoint::{PredictApiRequest, Prediction},
+        rerank_endpoint::{RerankApiRequest, RerankApiResponseItem},
+    },
+    settings::{HealthCheckSettings, LoadBalancingStrategy},
+};
+
+use super::{ApiClient, ApiClientResult};
+
+#[derive(Default)]
+struct UpstreamHealth {
+    failed_checks: u32,
+    consecutive_errors: u32,
+    is_down: bool,
+    ejected_until: Option<Instant>,
+}
+
+impl UpstreamHealth {
+    fn is_available(&self) -> bool {
+        !self.is_down
+            && self
+                .ejected_until
+                .is_none_or(|ejected_until| ejected_until <= Instant::now())
+    }
+}
+
+/// Current state of the upstream, as shown by the admin endpoint.
+#[derive(Debug, Serialize)]
+pub struct UpstreamStatus {
+    pub name: String,
+    pub available: bool,
+    pub failed_health_checks: u32,
+    pub consecutive_errors: u32,
+    pub ejected_for_ms: Option<u128>,
+    pub outstanding_batches: usize,
+}
+
+pub struct Upstream<TApiClient> {
+    pub name: String,
+    pub client: TApiClient,
+    pub weight: u32,
+    outstanding: AtomicUsize,
+    health: Mutex<UpstreamHealth>,
+}
+
+impl<TApiClient> Upstream<TApiClient> {
+    pub fn new(name: String, client: TApiClient, weight: u32) -> Self {
+        Self {
+            name,
+            client,
+            weight,
+            outstanding: AtomicUsize::new(0),
+            health: Mutex::new(UpstreamHealth::default()),
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.health.lock().unwrap().is_available()
+    }
+
+    fn record_health_check(&self, is_healthy: bool, settings: &HealthCheckSettings) {
+        let mut health = self.health.lock().unwrap();
+
+        if is_healthy {
+            if health.is_down {
+                info!("Upstream is healthy again. [upstream = {}]", self.name);
+            }
+            health.failed_checks = 0;
+            health.is_down = false;
+            return;
+        }
+
+        health.failed_checks += 1;
+        if !health.is_down && health.failed_checks >= settings.unhealthy_threshold {
+            warn!(
+                "Upstream failed health checks, marking it down. [upstream = {}, failed_checks = {}]",
+                self.name, health.failed_checks
+            );
+            health.is_down = true;
+        }
+    }
+
+    fn record_call(&self, is_upstream_failure: bool, settings: &HealthCheckSettings) {
+        let mut health = self.health.lock().unwrap();
+
+        if !is_upstream_failure {
+            health.consecutive_errors = 0;
+            return;
+        }
+
+        health.consecutive_errors += 1;
+        if health.consecutive_errors >= settings.ejection_threshold {
+            warn!(
+                "Upstream failed consecutive batches, ejecting it. [upstream = {}, consecutive_errors = {}]",
+                self.name, health.consecutive_errors
+            );
+            health.consecutive_errors = 0;
+            health.ejected_until =
+                Some(Instant::now() + Duration::from_millis(settings.ejection_cooldown_ms));
+        }
+    }
+
+    fn status(&self) -> UpstreamStatus {
+        let health = self.health.lock().unwrap();
+        let now = Instant::now();
+
+        UpstreamStatus {
+            name: self.name.clone(),
+            available: health.is_available(),
+            failed_health_checks: health.failed_checks,
+            consecutive_errors: health.consecutive_errors,
+            ejected_for_ms: health
+                .ejected_until
+                .filter(|ejected_until| *ejected_until > now)
+                .map(|ejected_until| (ejected_until - now).as_millis()),
+            outstanding_batches: self.outstanding.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Decrements the outstanding calls counter once the call completes or is cancelled.
+struct OutstandingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for OutstandingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Spreads calls between several upstream replicas. Every replica has its own client,
+/// so a slow replica does not hold up calls to the other ones.
+pub struct LoadBalancedApiClient<TApiClient> {
+    upstreams: Vec<Arc<Upstream<TApiClient>>>,
+    strategy: LoadBalancingStrategy,
+    health_settings: HealthCheckSettings,
+    next: AtomicUsize,
+}
+
+impl<TApiClient> LoadBalancedApiClient<TApiClient> {
+    pub fn new(
+        upstreams: Vec<Upstream<TApiClient>>,
+        strategy: LoadBalancingStrategy,
+        health_settings: HealthCheckSettings,
+    ) -> Self {
+        assert!(
+            !upstreams.is_empty(),
+            "At least one upstream has to be configured."
+        );
+
+        Self {
+            upstreams: upstreams.into_iter().map(Arc::new).collect(),
+            strategy,
+            health_settings,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn upstreams_status(&self) -> Vec<UpstreamStatus> {
+        self.upstreams.iter().map(|u| u.status()).collect()
+    }
+
+    /// Selects one of the available upstreams. If none is available, all of them are considered,
+    /// it is better to try a possibly unhealthy upstream than to fail the batch right away.
+    fn select(&self) -> &Upstream<TApiClient> {
+        let available: Vec<_> = self
+            .upstreams
+            .iter()
+            .filter(|upstream| upstream.is_available())
+            .collect();
+        let candidates = if available.is_empty() {
+            self.upstreams.iter().collect()
+        } else {
+            available
+        };
+
+        let upstream = match self.strategy {
+            LoadBalancingStrategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                candidates[next % candidates.len()]
+            }
+            LoadBalancingStrategy::LeastOutstanding => {
+                // Starting from a rotating offset spreads calls between equally loaded upstreams.
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+
+                (0..candidates.len())
+                    .map(|i| candidates[(offset + i) % candidates.len()])
+                    .min_by_key(|upstream| upstream.outstanding.load(Ordering::Relaxed))
+                    .expect("Upstreams are never empty.")
+            }
+            LoadBalancingStrategy::Weighted => {
+                let total_weight: u32 = candidates.iter().map(|u| u.weight).sum();
+                let mut point = rand::random_range(0..total_weight.max(1));
+
+                candidates
+                    .iter()
+                    .find(|upstream| {
+                        if point < upstream.weight {
+                            return true;
+                        }
+                        point -= upstream.weight;
+                        false
+                    })
+                    .unwrap_or(&candidates[0])
+            }
+        };
+
+        debug!("Selected upstream. [upstream = {}]", upstream.name);
+
+        upstream
+    }
+
+    async fn call<'a, TResponse, TFuture>(
+        &'a self,
+        call: impl FnOnce(&'a TApiClient) -> TFuture,
+    ) -> ApiClientResult<TResponse>
+    where
+        TFuture: Future<Output = ApiClientResult<TResponse>>,
+    {
+        let upstream = self.select();
+        upstream.outstanding.fetch_add(1, Ordering::Relaxed);
+        let _guard = OutstandingGuard(&upstream.outstanding);
+
+        let result = call(&upstream.client).await;
+        upstream.record_call(
+            result.as_ref().is_err_and(|e| e.is_upstream_failure()),
+            &self.health_settings,
+        );
+
+        result
+    }
+}
+
+impl<TApiClient: ApiClient> LoadBalancedApiClient<TApiClient> {
+    /// Starts polling the health endpoint of every upstream in the background.
+    pub fn start_health_checks(&self) {
+        let interval = Duration::from_millis(self.health_settings.interval_ms);
+
+        for upstream in &self.upstreams {
+            let upstream = Arc::clone(upstream);
+            let settings = self.health_settings.clone();
+
+            tokio::spawn(async move {
+                let mut check_interval = tokio::time::interval(interval);
+                loop {
+                    check_interval.tick().await;
+                    let is_healthy = tokio::time::timeout(interval, upstream.client.check_health())
+                        .await
+                        .is_ok_and(|result| result.is_ok());
+                    upstream.record_health_check(is_healthy, &settings);
+                }
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl<TApiClient: ApiClient> ApiClient for LoadBalancedApiClient<TApiClient> {
+    async fn check_health(&self) -> ApiClientResult<()> {
+        self.call(|client| client.check_health()).await
+    }
+
+    async fn tokenize(&self, texts: &[&str]) -> ApiClientResult<Vec<usize>> {
+        self.call(|client| client.tokenize(texts)).await
+    }
+
+    async fn call_embed(&self, request: &EmbedApiRequest) -> ApiClientResult<Vec<Vec<f64>>> {
+        self.call(|client| client.call_embed(request)).await
+    }
+
+    async fn call_embed_sparse(
+        &self,
+        request: &EmbedSparseApiRequest,
+    ) -> ApiClientResult<Vec<Vec<SparseValue>>> {
+        self.call(|client| client.call_embed_sparse(request)).await
+    }
+
+    async fn call_predict(
+        &self,
+        request: &PredictApiRequest,
+    ) -> ApiClientResult<Vec<Vec<Prediction>>> {
+        self.call(|client| client.call_predict(request)).await
+    }
+
+    async fn call_rerank(
+        &self,
+        request: &RerankApiRequest,
+    ) -> ApiClientResult<Vec<RerankApiResponseItem>> {
+        self.call(|client| client.call_rerank(request)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health_settings() -> HealthCheckSettings {
+        HealthCheckSettings {
+            interval_ms: 1000,
+            unhealthy_threshold: 2,
+            ejection_threshold: 2,
+            ejection_cooldown_ms: 100,
+        }
+    }
+
+    fn balancer(weights: &[u32], strategy: LoadBalancingStrategy) -> LoadBalancedApiClient<()> {
+        let upstreams = weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| Upstream::new(i.to_string(), (), *weight))
+            .collect();
+
+        LoadBalancedApiClient::new(upstreams, strategy, health_settings())
+    }
+
+    fn selected(balancer: &LoadBalancedApiClient<()>, count: usize) -> Vec<String> {
+        (0..count).map(|_| balancer.select().name.clone()).collect()
+    }
+
+    #[test]
+    fn given_round_robin_when_selecting_should_cycle_through_upstreams() {
+        let balancer = balancer(&[1, 1, 1], LoadBalancingStrategy::RoundRobin);
+
+        assert_eq!(selected(&balancer, 4), vec!["0", "1", "2", "0"]);
+    }
+
+    #[test]
+    fn given_least_outstanding_when_selecting_should_skip_busy_upstream() {
+        let balancer = balancer(&[1, 1], LoadBalancingStrategy::LeastOutstanding);
+        balancer.upstreams[0]
+            .outstanding
+            .fetch_add(1, Ordering::Relaxed);
+
+        assert_eq!(selected(&balancer, 3), vec!["1", "1", "1"]);
+    }
+
+    #[test]
+    fn given_weighted_when_upstream_has_zero_weight_should_never_select_it() {
+        let balancer = balancer(&[0, 3], LoadBalancingStrategy::Weighted);
+
+        assert!(selected(&balancer, 20).iter().all(|name| name == "1"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_consecutive_batch_errors_when_selecting_should_skip_upstream_until_cooldown() {
+        let balancer = balancer(&[1, 1], LoadBalancingStrategy::RoundRobin);
+        let settings = health_settings();
+        balancer.upstreams[0].record_call(true, &settings);
+        balancer.upstreams[0].record_call(true, &settings);
+
+        assert_eq!(selected(&balancer, 2), vec!["1", "1"]);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(balancer.upstreams[0].is_available());
+    }
+
+    #[test]
+    fn given_failed_health_checks_when_threshold_reached_should_mark_down_until_healthy() {
+        let balancer = balancer(&[1, 1], LoadBalancingStrategy::RoundRobin);
+        let settings = health_settings();
+        let upstream = &balancer.upstreams[1];
+
+        upstream.record_health_check(false, &settings);
+        assert!(upstream.is_available());
+
+        upstream.record_health_check(false, &settings);
+        assert!(!upstream.is_available());
+        assert_eq!(selected(&balancer, 2), vec!["0", "0"]);
+
+        upstream.record_health_check(true, &settings);
+        assert!(upstream.is_available());
+    }
+
+    #[test]
+    fn given_all_upstreams_down_when_selecting_should_fall_back_to_all() {
+        let balancer = balancer(&[1, 1], LoadBalancingStrategy::RoundRobin);
+        let settings = health_settings();
+        for upstream in &balancer.upstreams {
+            upstream.record_health_check(false, &settings);
+            upstream.record_health_check(false, &settings);
+        }
+
+        assert_eq!(selected(&balancer, 2), vec!["0", "1"]);
+    }
+}
@@ -1,38 +1,244 @@
 use async_trait::async_trait;
-use reqwest::Url;
+use std::time::Duration;
 
-use crate::api::endpoint::embed_endpoint::EmbedApiRequest;
+use reqwest::{Url, header::RETRY_AFTER};
+use serde::{
+    Deserialize, Serialize,
+    de::{DeserializeOwned, IgnoredAny},
+};
 
-use super::{ApiClient, ApiClientResult};
+use crate::{
+    api::endpoint::{
+        embed_endpoint::{EmbedApiRequest, EmbedApiRequestInputs},
+        embed_sparse_endpoint::{EmbedSparseApiRequest, SparseValue},
+        predict_endpoint::{PredictApiRequest, PredictApiRequestInputs, Prediction},
+        rerank_endpoint::{RerankApiRequest, RerankApiResponseItem},
+    },
+    settings::HttpClientSettings,
+};
+
+use super::{ApiClient, ApiClientError, ApiClientResult};
+
+/// Error body returned by the upstream API.
+#[derive(Deserialize)]
+struct UpstreamErrorBody {
+    error: String,
+    error_type: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TokenizeRequest<'a> {
+    inputs: &'a [&'a str],
+}
 
 pub struct ReqwestApiClient {
     embed_url: String,
+    embed_sparse_url: String,
+    predict_url: String,
+    rerank_url: String,
+    tokenize_url: String,
+    health_url: String,
+    request_timeout: Duration,
+    request_timeout_per_item: Duration,
     pub client: reqwest::Client,
 }
 
 impl ReqwestApiClient {
-    pub fn new(base_url: &str) -> anyhow::Result<Self> {
+    pub fn new(base_url: &str, settings: &HttpClientSettings) -> anyhow::Result<Self> {
         let base_url = Url::parse(base_url)?;
 
+        let mut client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(settings.connect_timeout_ms))
+            .pool_idle_timeout(Duration::from_millis(settings.pool_idle_timeout_ms))
+            .pool_max_idle_per_host(settings.pool_max_idle_per_host);
+        if settings.http2_prior_knowledge {
+            client = client.http2_prior_knowledge();
+        }
+
         Ok(Self {
             embed_url: base_url.join("/embed")?.to_string(),
-            client: reqwest::Client::new(),
+            embed_sparse_url: base_url.join("/embed_sparse")?.to_string(),
+            predict_url: base_url.join("/predict")?.to_string(),
+            rerank_url: base_url.join("/rerank")?.to_string(),
+            tokenize_url: base_url.join("/tokenize")?.to_string(),
+            health_url: base_url.join("/health")?.to_string(),
+            request_timeout: Duration::from_millis(settings.request_timeout_ms),
+            request_timeout_per_item: Duration::from_millis(settings.request_timeout_per_item_ms),
+            client: client.build()?,
         })
     }
+
+    /// Timeout of a single upstream call, larger batches take longer to process.
+    fn batch_timeout(&self, batch_size: usize) -> Duration {
+        self.request_timeout + self.request_timeout_per_item * batch_size as u32
+    }
+
+    async fn post_json<TRequest, TResponse>(
+        &self,
+        url: &str,
+        request: &TRequest,
+        batch_size: usize,
+    ) -> ApiClientResult<TResponse>
+    where
+        TRequest: Serialize + Sync,
+        TResponse: DeserializeOwned,
+    {
+        let response = self
+            .client
+            .post(url)
+            .timeout(self.batch_timeout(batch_size))
+            .json(request)
+            .send()
+            .await?;
+        let status = response.status();
+
+        if !status.is_success() {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs);
+            let body = response.text().await?;
+            let UpstreamErrorBody { error, error_type } =
+                serde_json::from_str(&body).unwrap_or(UpstreamErrorBody {
+                    error: body,
+                    error_type: None,
+                });
+
+            return Err(ApiClientError::Upstream {
+                status,
+                message: error,
+                error_type,
+                retry_after,
+            });
+        }
+
+        let result_json = response.json().await?;
+
+        Ok(result_json)
+    }
 }
 
 #[async_trait]
 impl ApiClient for ReqwestApiClient {
-    async fn call_embed(&self, request: &EmbedApiRequest) -> ApiClientResult<Vec<Vec<f64>>> {
-        let result_json = self
-            .client
-            .post(&self.embed_url)
-            .json(&request)
+    async fn check_health(&self) -> ApiClientResult<()> {
+        self.client
+            .get(&self.health_url)
+            .timeout(self.request_timeout)
             .send()
             .await?
-            .json()
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn tokenize(&self, texts: &[&str]) -> ApiClientResult<Vec<usize>> {
+        let tokens: Vec<Vec<IgnoredAny>> = self
+            .post_json(
+                &self.tokenize_url,
+                &TokenizeRequest { inputs: texts },
+                texts.len(),
+            )
             .await?;
 
-        Ok(result_json)
+        Ok(tokens.iter().map(Vec::len).collect())
+    }
+
+    async fn call_embed(&self, request: &EmbedApiRequest) -> ApiClientResult<Vec<Vec<f64>>> {
+        let batch_size = embed_batch_size(&request.inputs);
+        self.post_json(&self.embed_url, request, batch_size).await
+    }
+
+    async fn call_embed_sparse(
+        &self,
+        request: &EmbedSparseApiRequest,
+    ) -> ApiClientResult<Vec<Vec<SparseValue>>> {
+        let batch_size = embed_batch_size(&request.inputs);
+        self.post_json(&self.embed_sparse_url, request, batch_size)
+            .await
+    }
+
+    async fn call_predict(
+        &self,
+        request: &PredictApiRequest,
+    ) -> ApiClientResult<Vec<Vec<Prediction>>> {
+        let batch_size = match &request.inputs {
+            PredictApiRequestInputs::Single(_) => 1,
+            PredictApiRequestInputs::Batch(inputs) => inputs.len(),
+        };
+        self.post_json(&self.predict_url, request, batch_size).await
+    }
+
+    async fn call_rerank(
+        &self,
+        request: &RerankApiRequest,
+    ) -> ApiClientResult<Vec<RerankApiResponseItem>> {
+        self.post_json(&self.rerank_url, request, request.texts.len())
+            .await
+    }
+}
+
+fn embed_batch_size(inputs: &EmbedApiRequestInputs) -> usize {
+    match inputs {
+        EmbedApiRequestInputs::Single(_) => 1,
+        EmbedApiRequestInputs::Vec(inputs) => inputs.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::api::endpoint::embed_endpoint::EmbedInput;
+
+    use super::*;
+
+    fn http_client_settings(request_timeout_ms: u64) -> HttpClientSettings {
+        HttpClientSettings {
+            connect_timeout_ms: 1000,
+            request_timeout_ms,
+            request_timeout_per_item_ms: 10,
+            pool_idle_timeout_ms: 90_000,
+            pool_max_idle_per_host: 32,
+            http2_prior_knowledge: false,
+        }
+    }
+
+    #[test]
+    fn given_per_item_timeout_when_batch_is_larger_should_wait_longer() {
+        let client =
+            ReqwestApiClient::new("http://localhost:8080", &http_client_settings(100)).unwrap();
+
+        assert_eq!(client.batch_timeout(1), Duration::from_millis(110));
+        assert_eq!(client.batch_timeout(32), Duration::from_millis(420));
+    }
+
+    #[tokio::test]
+    async fn given_hung_upstream_when_called_should_fail_with_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // Accepts connections but never responds.
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        let client =
+            ReqwestApiClient::new(&format!("http://{address}"), &http_client_settings(50)).unwrap();
+        let request = EmbedApiRequest {
+            inputs: EmbedApiRequestInputs::Single(EmbedInput::Str("hello".to_string())),
+            dimensions: None,
+            normalize: None,
+            prompt_name: None,
+            truncate: None,
+            truncation_direction: None,
+        };
+
+        let result = client.call_embed(&request).await;
+
+        assert!(matches!(result, Err(ApiClientError::Timeout(_))));
     }
 }
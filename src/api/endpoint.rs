@@ -1,4 +1,7 @@
 pub mod embed_endpoint;
+pub mod embed_sparse_endpoint;
+pub mod predict_endpoint;
+pub mod rerank_endpoint;
 
 pub trait GroupingParams: Send + Sync {
     type DataItem;
@@ -6,16 +9,73 @@ pub trait GroupingParams: Send + Sync {
 
     fn to_request(&self, data: Vec<Self::DataItem>) -> Self::ApiRequest;
     fn decompose_api_request(api_request: Self::ApiRequest) -> (Vec<Self::DataItem>, Self);
+
+    /// Texts the upstream tokenizes together with every input, e.g. the rerank query.
+    /// Their tokens count against the token budget once per input.
+    fn shared_texts(&self) -> Vec<&str> {
+        Vec::new()
+    }
+}
+
+/// Input whose size can be measured in tokens, used for token-budget batching.
+pub trait MeasurableInput {
+    /// Texts that make up the input.
+    fn texts(&self) -> Vec<&str>;
+
+    /// Token count of an input that is already tokenized.
+    fn token_count(&self) -> Option<usize> {
+        None
+    }
+
+    /// Approximate heap memory held by the input, used to limit the size of the response cache.
+    fn heap_size(&self) -> usize {
+        self.texts().iter().map(|text| text.len()).sum()
+    }
+}
+
+/// Plain text inputs, e.g. the texts ranked against the rerank query.
+impl MeasurableInput for String {
+    fn texts(&self) -> Vec<&str> {
+        vec![self]
+    }
 }
 
 pub trait ApiEndpont: 'static {
+    /// Endpoint name, used in logs and metrics.
+    const NAME: &'static str;
+
     type ApiRequest: Send + Sync + std::fmt::Debug;
-    type ApiResponseItem: Send + Sync + std::fmt::Debug;
-    type DataItem: Send + Sync + std::fmt::Debug;
+    /// Results are cloned to every input that was deduplicated within a batch,
+    /// and serialized into the disk cache.
+    type ApiResponseItem: Send
+        + Sync
+        + Clone
+        + serde::Serialize
+        + serde::de::DeserializeOwned
+        + std::fmt::Debug;
+    type DataItem: Send
+        + Sync
+        + MeasurableInput
+        + std::hash::Hash
+        + Eq
+        + Clone
+        + serde::Serialize
+        + std::fmt::Debug;
     type GroupingParams: Send
         + GroupingParams<DataItem = Self::DataItem, ApiRequest = Self::ApiRequest>
         + std::hash::Hash
         + Eq
         + Clone
+        + serde::Serialize
         + std::fmt::Debug;
+
+    /// Checks endpoint-specific shape of the response, its length is checked by the executor.
+    fn validate_response(_response: &[Self::ApiResponseItem]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Approximate memory held by a response item, used to limit the size of the response cache.
+    fn response_item_size(item: &Self::ApiResponseItem) -> usize {
+        size_of_val(item)
+    }
 }
@@ -1,19 +1,51 @@
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
+use tokio::sync::watch;
 
 use crate::{
     api::{api_data_provider::ApiDataProvider, client::ApiClient},
     batch::{Batch, DataProvider},
+    error::ProxyResult,
 };
 
-use super::{ApiEndpont, GroupingParams};
+use super::{ApiEndpont, GroupingParams, MeasurableInput};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbedInput {
+    Str(String),
+    Ids(Vec<u32>),
+}
+
+impl MeasurableInput for EmbedInput {
+    fn texts(&self) -> Vec<&str> {
+        match self {
+            EmbedInput::Str(text) => vec![text],
+            EmbedInput::Ids(_) => Vec::new(),
+        }
+    }
+
+    fn token_count(&self) -> Option<usize> {
+        match self {
+            EmbedInput::Str(_) => None,
+            EmbedInput::Ids(ids) => Some(ids.len()),
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        match self {
+            EmbedInput::Str(text) => text.len(),
+            EmbedInput::Ids(ids) => size_of_val(ids.as_slice()),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum EmbedApiRequestInputs {
-    Vec(Vec<String>),
-    Str(String),
+    Vec(Vec<EmbedInput>),
+    Single(EmbedInput),
 }
 
 impl Default for EmbedApiRequestInputs {
@@ -36,13 +68,36 @@ pub struct EmbedApiRequest {
 pub struct EmbedApiEndpoint;
 
 impl ApiEndpont for EmbedApiEndpoint {
+    const NAME: &'static str = "embed";
+
     type ApiRequest = EmbedApiRequest;
     type ApiResponseItem = Vec<f64>;
-    type DataItem = String;
+    type DataItem = EmbedInput;
     type GroupingParams = EmbedRequestGroupingParams;
+
+    fn response_item_size(embedding: &Vec<f64>) -> usize {
+        size_of_val(embedding) + size_of_val(embedding.as_slice())
+    }
+
+    fn validate_response(response: &[Vec<f64>]) -> Result<(), String> {
+        let Some(expected) = response.first().map(Vec::len) else {
+            return Ok(());
+        };
+
+        match response
+            .iter()
+            .find(|embedding| embedding.len() != expected)
+        {
+            Some(embedding) => Err(format!(
+                "Inconsistent embedding dimensions, expected {expected}, got {}.",
+                embedding.len()
+            )),
+            None => Ok(()),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Serialize, PartialEq, Eq, Hash, Clone)]
 pub struct EmbedRequestGroupingParams {
     pub dimensions: Option<usize>,
     pub normalize: Option<bool>,
@@ -52,7 +107,7 @@ pub struct EmbedRequestGroupingParams {
 }
 
 impl GroupingParams for EmbedRequestGroupingParams {
-    type DataItem = String;
+    type DataItem = EmbedInput;
     type ApiRequest = EmbedApiRequest;
 
     fn to_request(&self, data: Vec<Self::DataItem>) -> Self::ApiRequest {
@@ -77,7 +132,7 @@ impl GroupingParams for EmbedRequestGroupingParams {
         } = api_request;
 
         let request_data = match inputs {
-            EmbedApiRequestInputs::Str(input) => vec![input],
+            EmbedApiRequestInputs::Single(input) => vec![input],
             EmbedApiRequestInputs::Vec(inputs) => inputs,
         };
 
@@ -98,9 +153,30 @@ impl<TApiClient: ApiClient> DataProvider<EmbedApiEndpoint> for ApiDataProvider<T
     async fn get_data_for_batch(
         &self,
         batch: &Batch<EmbedApiEndpoint>,
-    ) -> anyhow::Result<Vec<Vec<f64>>> {
-        let response = self.api_client.call_embed(batch.api_parameters()).await?;
+    ) -> ProxyResult<Vec<Vec<f64>>> {
+        let response = self
+            .retry_policy
+            .run(batch.deadline(), || {
+                self.api_client.call_embed(batch.api_parameters())
+            })
+            .await?;
 
         Ok(response)
     }
+
+    fn availability(&self) -> watch::Receiver<bool> {
+        self.availability.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_inconsistent_dimensions_when_validated_should_return_error() {
+        let response = vec![vec![0.1, 0.2], vec![0.3]];
+
+        assert!(EmbedApiEndpoint::validate_response(&response).is_err());
+    }
 }
@@ -0,0 +1,165 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use tokio::sync::watch;
+
+use crate::{
+    api::{api_data_provider::ApiDataProvider, client::ApiClient},
+    batch::{Batch, DataProvider},
+    error::ProxyResult,
+};
+
+use super::{
+    ApiEndpont, GroupingParams,
+    embed_endpoint::{EmbedApiRequestInputs, EmbedInput},
+};
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbedSparseApiRequest {
+    pub inputs: EmbedApiRequestInputs,
+    pub prompt_name: Option<String>,
+    pub truncate: Option<bool>,
+    pub truncation_direction: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SparseValue {
+    pub index: usize,
+    pub value: f64,
+}
+
+pub struct EmbedSparseApiEndpoint;
+
+impl ApiEndpont for EmbedSparseApiEndpoint {
+    const NAME: &'static str = "embed_sparse";
+
+    type ApiRequest = EmbedSparseApiRequest;
+    type ApiResponseItem = Vec<SparseValue>;
+    type DataItem = EmbedInput;
+    type GroupingParams = EmbedSparseRequestGroupingParams;
+
+    fn response_item_size(embedding: &Vec<SparseValue>) -> usize {
+        size_of_val(embedding) + size_of_val(embedding.as_slice())
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Hash, Clone)]
+pub struct EmbedSparseRequestGroupingParams {
+    pub prompt_name: Option<String>,
+    pub truncate: Option<bool>,
+    pub truncation_direction: Option<String>,
+}
+
+impl GroupingParams for EmbedSparseRequestGroupingParams {
+    type DataItem = EmbedInput;
+    type ApiRequest = EmbedSparseApiRequest;
+
+    fn to_request(&self, data: Vec<Self::DataItem>) -> Self::ApiRequest {
+        EmbedSparseApiRequest {
+            inputs: EmbedApiRequestInputs::Vec(data),
+            prompt_name: self.prompt_name.clone(),
+            truncate: self.truncate,
+            truncation_direction: self.truncation_direction.clone(),
+        }
+    }
+
+    fn decompose_api_request(api_request: Self::ApiRequest) -> (Vec<Self::DataItem>, Self) {
+        let EmbedSparseApiRequest {
+            inputs,
+            prompt_name,
+            truncate,
+            truncation_direction,
+        } = api_request;
+
+        let request_data = match inputs {
+            EmbedApiRequestInputs::Single(input) => vec![input],
+            EmbedApiRequestInputs::Vec(inputs) => inputs,
+        };
+
+        let request_params = EmbedSparseRequestGroupingParams {
+            prompt_name,
+            truncate,
+            truncation_direction,
+        };
+
+        (request_data, request_params)
+    }
+}
+
+#[async_trait]
+impl<TApiClient: ApiClient> DataProvider<EmbedSparseApiEndpoint> for ApiDataProvider<TApiClient> {
+    async fn get_data_for_batch(
+        &self,
+        batch: &Batch<EmbedSparseApiEndpoint>,
+    ) -> ProxyResult<Vec<Vec<SparseValue>>> {
+        let response = self
+            .retry_policy
+            .run(batch.deadline(), || {
+                self.api_client.call_embed_sparse(batch.api_parameters())
+            })
+            .await?;
+
+        Ok(response)
+    }
+
+    fn availability(&self) -> watch::Receiver<bool> {
+        self.availability.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn given_single_input_when_decomposed_should_keep_params_for_grouping() {
+        let request: EmbedSparseApiRequest =
+            serde_json::from_str(r#"{"inputs": "a", "prompt_name": "query", "truncate": true}"#)
+                .unwrap();
+
+        let (data, params) = EmbedSparseRequestGroupingParams::decompose_api_request(request);
+
+        assert_eq!(data, vec![EmbedInput::Str("a".to_string())]);
+        assert_eq!(
+            params,
+            EmbedSparseRequestGroupingParams {
+                prompt_name: Some("query".to_string()),
+                truncate: Some(true),
+                truncation_direction: None,
+            }
+        );
+    }
+
+    #[test]
+    fn given_batch_when_converted_to_request_should_send_params_and_skip_unset_ones() {
+        let params = EmbedSparseRequestGroupingParams {
+            prompt_name: Some("query".to_string()),
+            truncate: None,
+            truncation_direction: None,
+        };
+
+        let request = params.to_request(vec![
+            EmbedInput::Str("a".to_string()),
+            EmbedInput::Ids(vec![1, 2]),
+        ]);
+
+        assert_eq!(
+            serde_json::to_value(request).unwrap(),
+            json!({ "inputs": ["a", [1, 2]], "prompt_name": "query" })
+        );
+    }
+
+    #[test]
+    fn given_upstream_response_when_parsed_should_round_trip_sparse_values() {
+        let body = json!([[{ "index": 3, "value": 0.5 }, { "index": 10, "value": 1.25 }], []]);
+
+        let response: Vec<Vec<SparseValue>> = serde_json::from_value(body.clone()).unwrap();
+
+        assert_eq!(response[0][1].index, 10);
+        assert!(response[1].is_empty());
+        assert_eq!(serde_json::to_value(&response).unwrap(), body);
+    }
+}
@@ -0,0 +1,194 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use tokio::sync::watch;
+
+use crate::{
+    api::{api_data_provider::ApiDataProvider, client::ApiClient},
+    batch::{Batch, DataProvider},
+    error::ProxyResult,
+};
+
+use super::{ApiEndpont, GroupingParams, MeasurableInput};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PredictInput {
+    Single(String),
+    Pair(String, String),
+}
+
+impl MeasurableInput for PredictInput {
+    fn texts(&self) -> Vec<&str> {
+        match self {
+            PredictInput::Single(text) => vec![text],
+            PredictInput::Pair(first, second) => vec![first, second],
+        }
+    }
+}
+
+/// Variant order matters, `["a", "b"]` is a single text pair, same as in the upstream API.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PredictApiRequestInputs {
+    Single(PredictInput),
+    Batch(Vec<PredictInput>),
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PredictApiRequest {
+    pub inputs: PredictApiRequestInputs,
+    pub raw_scores: Option<bool>,
+    pub truncate: Option<bool>,
+    pub truncation_direction: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Prediction {
+    pub label: String,
+    pub score: f64,
+}
+
+pub struct PredictApiEndpoint;
+
+impl ApiEndpont for PredictApiEndpoint {
+    const NAME: &'static str = "predict";
+
+    type ApiRequest = PredictApiRequest;
+    type ApiResponseItem = Vec<Prediction>;
+    type DataItem = PredictInput;
+    type GroupingParams = PredictRequestGroupingParams;
+
+    fn response_item_size(predictions: &Vec<Prediction>) -> usize {
+        size_of_val(predictions)
+            + predictions
+                .iter()
+                .map(|prediction| size_of_val(prediction) + prediction.label.len())
+                .sum::<usize>()
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Hash, Clone)]
+pub struct PredictRequestGroupingParams {
+    pub raw_scores: Option<bool>,
+    pub truncate: Option<bool>,
+    pub truncation_direction: Option<String>,
+}
+
+impl GroupingParams for PredictRequestGroupingParams {
+    type DataItem = PredictInput;
+    type ApiRequest = PredictApiRequest;
+
+    fn to_request(&self, data: Vec<Self::DataItem>) -> Self::ApiRequest {
+        PredictApiRequest {
+            inputs: PredictApiRequestInputs::Batch(data),
+            raw_scores: self.raw_scores,
+            truncate: self.truncate,
+            truncation_direction: self.truncation_direction.clone(),
+        }
+    }
+
+    fn decompose_api_request(api_request: Self::ApiRequest) -> (Vec<Self::DataItem>, Self) {
+        let PredictApiRequest {
+            inputs,
+            raw_scores,
+            truncate,
+            truncation_direction,
+        } = api_request;
+
+        let request_data = match inputs {
+            PredictApiRequestInputs::Single(input) => vec![input],
+            PredictApiRequestInputs::Batch(inputs) => inputs,
+        };
+
+        let request_params = PredictRequestGroupingParams {
+            raw_scores,
+            truncate,
+            truncation_direction,
+        };
+
+        (request_data, request_params)
+    }
+}
+
+#[async_trait]
+impl<TApiClient: ApiClient> DataProvider<PredictApiEndpoint> for ApiDataProvider<TApiClient> {
+    async fn get_data_for_batch(
+        &self,
+        batch: &Batch<PredictApiEndpoint>,
+    ) -> ProxyResult<Vec<Vec<Prediction>>> {
+        let request = batch.api_parameters();
+
+        // Two single texts would be serialized as `["a", "b"]` and treated as a pair upstream,
+        // so they are sent one by one instead.
+        if let PredictApiRequestInputs::Batch(inputs) = &request.inputs
+            && let [PredictInput::Single(first), PredictInput::Single(second)] = inputs.as_slice()
+        {
+            let mut response = Vec::with_capacity(2);
+            for input in [first, second] {
+                let single_request = PredictApiRequest {
+                    inputs: PredictApiRequestInputs::Batch(vec![PredictInput::Single(
+                        input.clone(),
+                    )]),
+                    raw_scores: request.raw_scores,
+                    truncate: request.truncate,
+                    truncation_direction: request.truncation_direction.clone(),
+                };
+
+                response.extend(
+                    self.retry_policy
+                        .run(batch.deadline(), || {
+                            self.api_client.call_predict(&single_request)
+                        })
+                        .await?,
+                );
+            }
+
+            return Ok(response);
+        }
+
+        let response = self
+            .retry_policy
+            .run(batch.deadline(), || self.api_client.call_predict(request))
+            .await?;
+
+        Ok(response)
+    }
+
+    fn availability(&self) -> watch::Receiver<bool> {
+        self.availability.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompose(json: &str) -> Vec<PredictInput> {
+        let request: PredictApiRequest = serde_json::from_str(json).unwrap();
+        let (data, _) = PredictRequestGroupingParams::decompose_api_request(request);
+        data
+    }
+
+    #[test]
+    fn given_two_texts_when_decomposed_should_treat_them_as_pair() {
+        let data = decompose(r#"{"inputs": ["a", "b"]}"#);
+
+        assert!(matches!(data.as_slice(), [PredictInput::Pair(_, _)]));
+    }
+
+    #[test]
+    fn given_batch_of_pairs_and_texts_when_decomposed_should_keep_every_input() {
+        let data = decompose(r#"{"inputs": [["a", "b"], "c", ["d", "e"]]}"#);
+
+        assert!(matches!(
+            data.as_slice(),
+            [
+                PredictInput::Pair(_, _),
+                PredictInput::Single(_),
+                PredictInput::Pair(_, _)
+            ]
+        ));
+    }
+}
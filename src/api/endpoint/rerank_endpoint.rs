@@ -0,0 +1,173 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use tokio::sync::watch;
+
+use crate::{
+    api::{api_data_provider::ApiDataProvider, client::ApiClient},
+    batch::{Batch, DataProvider},
+    error::ProxyResult,
+};
+
+use super::{ApiEndpont, GroupingParams};
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RerankApiRequest {
+    pub query: String,
+    pub texts: Vec<String>,
+    pub raw_scores: Option<bool>,
+    pub return_text: Option<bool>,
+    pub truncate: Option<bool>,
+    pub truncation_direction: Option<String>,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankApiResponseItem {
+    pub index: usize,
+    pub score: f64,
+    pub text: Option<String>,
+}
+
+pub struct RerankApiEndpoint;
+
+impl ApiEndpont for RerankApiEndpoint {
+    const NAME: &'static str = "rerank";
+
+    type ApiRequest = RerankApiRequest;
+    type ApiResponseItem = RerankApiResponseItem;
+    type DataItem = String;
+    type GroupingParams = RerankRequestGroupingParams;
+
+    fn response_item_size(item: &RerankApiResponseItem) -> usize {
+        size_of_val(item) + item.text.as_ref().map_or(0, String::len)
+    }
+
+    /// Results are sorted by index by the data provider, so every index has to match its position.
+    fn validate_response(response: &[RerankApiResponseItem]) -> Result<(), String> {
+        match response
+            .iter()
+            .enumerate()
+            .find(|(position, item)| item.index != *position)
+        {
+            Some((position, item)) => Err(format!(
+                "Unexpected result index, expected {position}, got {}.",
+                item.index
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Hash, Clone)]
+pub struct RerankRequestGroupingParams {
+    pub query: String,
+    pub raw_scores: Option<bool>,
+    pub return_text: Option<bool>,
+    pub truncate: Option<bool>,
+    pub truncation_direction: Option<String>,
+}
+
+impl GroupingParams for RerankRequestGroupingParams {
+    type DataItem = String;
+    type ApiRequest = RerankApiRequest;
+
+    fn to_request(&self, data: Vec<Self::DataItem>) -> Self::ApiRequest {
+        RerankApiRequest {
+            query: self.query.clone(),
+            texts: data,
+            raw_scores: self.raw_scores,
+            return_text: self.return_text,
+            truncate: self.truncate,
+            truncation_direction: self.truncation_direction.clone(),
+        }
+    }
+
+    fn decompose_api_request(api_request: Self::ApiRequest) -> (Vec<Self::DataItem>, Self) {
+        let RerankApiRequest {
+            query,
+            texts,
+            raw_scores,
+            return_text,
+            truncate,
+            truncation_direction,
+        } = api_request;
+
+        let request_params = RerankRequestGroupingParams {
+            query,
+            raw_scores,
+            return_text,
+            truncate,
+            truncation_direction,
+        };
+
+        (texts, request_params)
+    }
+
+    /// Every text is scored as a pair with the query.
+    fn shared_texts(&self) -> Vec<&str> {
+        vec![&self.query]
+    }
+}
+
+/// Re-indexes results received from the batch into the client's own positions
+/// and sorts them by score, same as the upstream API does.
+pub fn into_client_response(results: Vec<RerankApiResponseItem>) -> Vec<RerankApiResponseItem> {
+    let mut results: Vec<_> = results
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| RerankApiResponseItem { index, ..item })
+        .collect();
+
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    results
+}
+
+#[async_trait]
+impl<TApiClient: ApiClient> DataProvider<RerankApiEndpoint> for ApiDataProvider<TApiClient> {
+    async fn get_data_for_batch(
+        &self,
+        batch: &Batch<RerankApiEndpoint>,
+    ) -> ProxyResult<Vec<RerankApiResponseItem>> {
+        let mut response = self
+            .retry_policy
+            .run(batch.deadline(), || {
+                self.api_client.call_rerank(batch.api_parameters())
+            })
+            .await?;
+
+        // Upstream sorts results by score, batch distribution expects them in input order.
+        response.sort_by_key(|item| item.index);
+
+        Ok(response)
+    }
+
+    fn availability(&self) -> watch::Receiver<bool> {
+        self.availability.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(index: usize, score: f64) -> RerankApiResponseItem {
+        RerankApiResponseItem {
+            index,
+            score,
+            text: None,
+        }
+    }
+
+    #[test]
+    fn given_batch_slice_when_converted_should_reindex_and_sort_by_score() {
+        let results = vec![item(4, 0.1), item(5, 0.9), item(6, 0.5)];
+
+        let response = into_client_response(results);
+
+        let indices: Vec<_> = response.iter().map(|i| i.index).collect();
+        assert_eq!(indices, vec![1, 2, 0]);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod api_data_provider;
 pub mod client;
 pub mod endpoint;
+pub mod openai;
+pub mod retry;
@@ -0,0 +1,266 @@
+use std::fmt;
+
+use actix_web::{HttpResponse, ResponseError, http::StatusCode};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+
+use crate::error::ProxyError;
+
+use super::endpoint::embed_endpoint::{EmbedApiRequest, EmbedApiRequestInputs, EmbedInput};
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodingFormat {
+    #[default]
+    Float,
+    Base64,
+}
+
+/// Request body of the OpenAI-compatible `/v1/embeddings` endpoint.
+#[derive(Debug, Deserialize)]
+pub struct OpenAiEmbeddingsRequest {
+    pub input: EmbedApiRequestInputs,
+    pub model: String,
+    pub dimensions: Option<usize>,
+    #[serde(default)]
+    pub encoding_format: EncodingFormat,
+}
+
+impl OpenAiEmbeddingsRequest {
+    /// Token ids are counted exactly, text inputs are estimated by whitespace-separated words.
+    /// Used when batches are not limited by a token budget, so inputs are not counted otherwise.
+    pub fn estimated_prompt_tokens(&self) -> usize {
+        let count = |input: &EmbedInput| match input {
+            EmbedInput::Str(text) => text.split_whitespace().count(),
+            EmbedInput::Ids(ids) => ids.len(),
+        };
+
+        match &self.input {
+            EmbedApiRequestInputs::Single(input) => count(input),
+            EmbedApiRequestInputs::Vec(inputs) => inputs.iter().map(count).sum(),
+        }
+    }
+
+    pub fn into_embed_request(self) -> EmbedApiRequest {
+        EmbedApiRequest {
+            inputs: self.input,
+            dimensions: self.dimensions,
+            normalize: None,
+            prompt_name: None,
+            truncate: None,
+            truncation_direction: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum OpenAiEmbeddingValue {
+    Float(Vec<f64>),
+    Base64(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpenAiEmbedding {
+    pub object: &'static str,
+    pub embedding: OpenAiEmbeddingValue,
+    pub index: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpenAiUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpenAiEmbeddingsResponse {
+    pub object: &'static str,
+    pub data: Vec<OpenAiEmbedding>,
+    pub model: String,
+    pub usage: OpenAiUsage,
+}
+
+impl OpenAiEmbeddingsResponse {
+    pub fn new(
+        embeddings: Vec<Vec<f64>>,
+        model: String,
+        encoding_format: EncodingFormat,
+        prompt_tokens: usize,
+    ) -> Self {
+        let data = embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| OpenAiEmbedding {
+                object: "embedding",
+                embedding: match encoding_format {
+                    EncodingFormat::Float => OpenAiEmbeddingValue::Float(embedding),
+                    EncodingFormat::Base64 => {
+                        OpenAiEmbeddingValue::Base64(encode_base64(&embedding))
+                    }
+                },
+                index,
+            })
+            .collect();
+
+        Self {
+            object: "list",
+            data,
+            model,
+            usage: OpenAiUsage {
+                prompt_tokens,
+                total_tokens: prompt_tokens,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OpenAiErrorBody<'a> {
+    message: &'a str,
+    #[serde(rename = "type")]
+    error_type: &'a str,
+    param: Option<&'a str>,
+    code: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenAiErrorResponse<'a> {
+    error: OpenAiErrorBody<'a>,
+}
+
+/// Error of the `/v1/embeddings` endpoint, sent in the OpenAI format
+/// `{"error": {"message": "...", "type": "...", "param": null, "code": "..."}}`.
+#[derive(Debug)]
+pub struct OpenAiError {
+    status: StatusCode,
+    message: String,
+    /// Error type of the proxy or the upstream, e.g. `validation`.
+    code: Option<String>,
+}
+
+impl OpenAiError {
+    fn error_type(&self) -> &'static str {
+        match self.status.as_u16() {
+            429 => "rate_limit_error",
+            400..=499 => "invalid_request_error",
+            _ => "server_error",
+        }
+    }
+}
+
+impl fmt::Display for OpenAiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl ResponseError for OpenAiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(OpenAiErrorResponse {
+            error: OpenAiErrorBody {
+                message: &self.message,
+                error_type: self.error_type(),
+                param: None,
+                code: self.code.as_deref(),
+            },
+        })
+    }
+}
+
+impl From<ProxyError> for OpenAiError {
+    fn from(error: ProxyError) -> Self {
+        Self {
+            status: error.status_code(),
+            message: error.to_string(),
+            code: Some(error.error_type().to_string()),
+        }
+    }
+}
+
+/// Request body and header errors.
+impl From<actix_web::Error> for OpenAiError {
+    fn from(error: actix_web::Error) -> Self {
+        Self {
+            status: error.as_response_error().status_code(),
+            message: error.to_string(),
+            code: None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for OpenAiError {
+    fn from(error: serde_json::Error) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: error.to_string(),
+            code: None,
+        }
+    }
+}
+
+/// OpenAI encodes embeddings as little-endian `f32` bytes.
+fn encode_base64(embedding: &[f64]) -> String {
+    let bytes: Vec<u8> = embedding
+        .iter()
+        .flat_map(|value| (*value as f32).to_le_bytes())
+        .collect();
+
+    STANDARD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_token_arrays_when_parsed_should_count_tokens_exactly() {
+        let request: OpenAiEmbeddingsRequest =
+            serde_json::from_str(r#"{"input": [[1, 2, 3], [4]], "model": "m"}"#).unwrap();
+
+        assert_eq!(request.estimated_prompt_tokens(), 4);
+        assert_eq!(request.encoding_format, EncodingFormat::Float);
+    }
+
+    #[test]
+    fn given_base64_format_when_response_built_should_encode_f32_bytes() {
+        let response =
+            OpenAiEmbeddingsResponse::new(vec![vec![1.0]], "m".into(), EncodingFormat::Base64, 1);
+
+        match &response.data[0].embedding {
+            OpenAiEmbeddingValue::Base64(value) => assert_eq!(value, "AACAPw=="),
+            OpenAiEmbeddingValue::Float(_) => panic!("Expected base64 embedding."),
+        }
+    }
+
+    #[tokio::test]
+    async fn given_upstream_error_when_converted_should_use_openai_envelope() {
+        let error = OpenAiError::from(ProxyError::Upstream {
+            status: 413,
+            message: "Batch is too large".to_string(),
+            error_type: Some("validation".to_string()),
+        });
+
+        let body = actix_web::body::to_bytes(error.error_response().into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(error.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": {
+                    "message": "Batch is too large",
+                    "type": "invalid_request_error",
+                    "param": null,
+                    "code": "validation"
+                }
+            })
+        );
+    }
+}
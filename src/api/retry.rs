@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+use log::warn;
+use metrics::counter;
+use tokio::time::Instant;
+
+use crate::{error::ProxyResult, settings::RetrySettings};
+
+use super::client::{ApiClientError, ApiClientResult};
+
+/// Retries transient upstream failures with exponential backoff and jitter.
+pub struct RetryPolicy {
+    settings: RetrySettings,
+}
+
+impl RetryPolicy {
+    pub fn new(settings: RetrySettings) -> Self {
+        Self { settings }
+    }
+
+    /// Runs the call until it succeeds, fails with a non-transient error, runs out of attempts
+    /// or the next attempt would start after the deadline.
+    pub async fn run<TResponse, TCall, TFuture>(
+        &self,
+        deadline: Option<Instant>,
+        call: TCall,
+    ) -> ProxyResult<TResponse>
+    where
+        TCall: Fn() -> TFuture,
+        TFuture: Future<Output = ApiClientResult<TResponse>>,
+    {
+        let mut retry = 0;
+
+        loop {
+            let error = match call().await {
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+
+            retry += 1;
+            let Some(delay) = self.retry_delay(&error, retry) else {
+                return Err(error.into());
+            };
+
+            if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+                warn!("Not retrying upstream call, batch deadline would pass. [error = {error}]");
+                return Err(error.into());
+            }
+
+            warn!(
+                "Upstream call failed, retrying. [retry = {retry}, delay = {delay:?}, error = {error}]"
+            );
+            counter!("batch_proxy_upstream_retries_total").increment(1);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Delay before the retry, `None` if the error should not be retried. Upstreams asking
+    /// to wait longer than `max_backoff_ms` are not retried.
+    fn retry_delay(&self, error: &ApiClientError, retry: u32) -> Option<Duration> {
+        if retry > self.settings.max_retries {
+            return None;
+        }
+
+        match error {
+            ApiClientError::Connect(_) => Some(self.backoff(retry)),
+            ApiClientError::Upstream {
+                status,
+                retry_after,
+                ..
+            } if status.as_u16() == 429 || status.as_u16() == 503 => match retry_after {
+                Some(retry_after)
+                    if *retry_after > Duration::from_millis(self.settings.max_backoff_ms) =>
+                {
+                    None
+                }
+                Some(retry_after) => Some(*retry_after),
+                None => Some(self.backoff(retry)),
+            },
+            _ => None,
+        }
+    }
+
+    /// Exponential backoff with equal jitter, half of the delay is randomized.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff_ms = self
+            .settings
+            .initial_backoff_ms
+            .saturating_mul(2u64.saturating_pow(retry - 1))
+            .min(self.settings.max_backoff_ms);
+        let jitter_ms = rand::random_range(0..=backoff_ms / 2);
+
+        Duration::from_millis(backoff_ms - backoff_ms / 2 + jitter_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use reqwest::StatusCode;
+
+    use crate::error::ProxyError;
+
+    use super::*;
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy::new(RetrySettings {
+            max_retries,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+        })
+    }
+
+    fn upstream_error(status: StatusCode, retry_after: Option<Duration>) -> ApiClientError {
+        ApiClientError::Upstream {
+            status,
+            message: "error".to_string(),
+            error_type: None,
+            retry_after,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_transient_failures_when_called_should_retry_until_success() {
+        let attempts = AtomicU32::new(0);
+
+        let result = policy(3)
+            .run(None, || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(upstream_error(StatusCode::SERVICE_UNAVAILABLE, None)),
+                    _ => Ok(42),
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_retry_after_when_overloaded_should_wait_requested_time() {
+        let attempts = AtomicU32::new(0);
+        let started_at = Instant::now();
+
+        let result = policy(3)
+            .run(None, || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(upstream_error(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        Some(Duration::from_millis(800)),
+                    )),
+                    _ => Ok(()),
+                }
+            })
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(started_at.elapsed(), Duration::from_millis(800));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_retry_after_over_max_backoff_when_overloaded_should_not_retry() {
+        let attempts = AtomicU32::new(0);
+
+        let result: ProxyResult<()> = policy(3)
+            .run(None, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(upstream_error(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some(Duration::from_secs(60)),
+                ))
+            })
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(ProxyError::Upstream { status: 429, .. })
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_deadline_before_next_attempt_when_failed_should_not_retry() {
+        let attempts = AtomicU32::new(0);
+        let deadline = Instant::now() + Duration::from_millis(10);
+
+        let result: ProxyResult<()> = policy(3)
+            .run(Some(deadline), || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(upstream_error(StatusCode::SERVICE_UNAVAILABLE, None))
+            })
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(ProxyError::Upstream { status: 503, .. })
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_validation_error_when_called_should_not_retry() {
+        let attempts = AtomicU32::new(0);
+
+        let result: ProxyResult<()> = policy(3)
+            .run(None, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(upstream_error(StatusCode::UNPROCESSABLE_ENTITY, None))
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,203 @@
+use std::{sync::Mutex, time::Duration};
+
+use metrics::histogram;
+use tokio::time::Instant;
+
+use crate::settings::AdaptiveBatchingSettings;
+
+/// Adjusts the flush window and the batch size of a worker to the observed load.
+///
+/// The batch size targets the number of inputs that arrive while one batch is executed upstream,
+/// so that under load the next batch is full by the time the previous one completes. The window
+/// is the time it takes to collect that many inputs. When traffic is sparse and waiting would
+/// not add anything to the batch, the shortest window is used.
+pub struct AdaptiveBatching {
+    endpoint: &'static str,
+    min_batch_size: usize,
+    max_batch_size: usize,
+    min_waiting_time: Duration,
+    max_waiting_time: Duration,
+    smoothing_factor: f64,
+    state: Mutex<AdaptiveBatchingState>,
+}
+
+struct AdaptiveBatchingState {
+    /// Moving average of the time between two arriving inputs, in seconds.
+    input_interval: f64,
+    /// Moving average of the batch execution time, in seconds.
+    upstream_latency: f64,
+    last_arrival_at: Option<Instant>,
+    batch_size: usize,
+    waiting_time: Duration,
+}
+
+impl AdaptiveBatching {
+    pub fn new(
+        endpoint: &'static str,
+        settings: &AdaptiveBatchingSettings,
+        max_batch_size: usize,
+        max_waiting_time_ms: u64,
+    ) -> Self {
+        let max_waiting_time = Duration::from_millis(max_waiting_time_ms);
+
+        Self {
+            endpoint,
+            min_batch_size: settings.min_batch_size.clamp(1, max_batch_size),
+            max_batch_size,
+            min_waiting_time: Duration::from_millis(settings.min_waiting_time_ms)
+                .min(max_waiting_time),
+            max_waiting_time,
+            smoothing_factor: settings.smoothing_factor.clamp(0.01, 1.0),
+            // Starts as if the traffic was sparse, until arrivals are observed.
+            state: Mutex::new(AdaptiveBatchingState {
+                input_interval: max_waiting_time.as_secs_f64(),
+                upstream_latency: max_waiting_time.as_secs_f64(),
+                last_arrival_at: None,
+                batch_size: max_batch_size,
+                waiting_time: Duration::from_millis(settings.min_waiting_time_ms)
+                    .min(max_waiting_time),
+            }),
+        }
+    }
+
+    pub fn record_arrival(&self, data_count: usize) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(last_arrival_at) = state.last_arrival_at.replace(now) {
+            let interval = (now - last_arrival_at).as_secs_f64() / data_count.max(1) as f64;
+            state.input_interval = self.smooth(state.input_interval, interval);
+            self.update(&mut state);
+        }
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+
+        state.upstream_latency = self.smooth(state.upstream_latency, latency.as_secs_f64());
+        self.update(&mut state);
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.state.lock().unwrap().batch_size
+    }
+
+    pub fn waiting_time(&self) -> Duration {
+        self.state.lock().unwrap().waiting_time
+    }
+
+    fn smooth(&self, average: f64, value: f64) -> f64 {
+        self.smoothing_factor * value + (1.0 - self.smoothing_factor) * average
+    }
+
+    fn update(&self, state: &mut AdaptiveBatchingState) {
+        let inputs_per_second = 1.0 / state.input_interval.max(f64::EPSILON);
+
+        let inputs_during_latency = (inputs_per_second * state.upstream_latency).ceil() as usize;
+        state.batch_size = inputs_during_latency.clamp(self.min_batch_size, self.max_batch_size);
+
+        let inputs_during_max_window = inputs_per_second * self.max_waiting_time.as_secs_f64();
+        state.waiting_time = if inputs_during_max_window < 1.0 {
+            self.min_waiting_time
+        } else {
+            let waiting_time_ms = (state.batch_size as f64 / inputs_per_second * 1000.0).round();
+            Duration::from_millis(waiting_time_ms as u64)
+                .clamp(self.min_waiting_time, self.max_waiting_time)
+        };
+
+        // Every worker adapts on its own, so values are recorded into a distribution
+        // rather than a gauge that workers of the same endpoint would overwrite.
+        histogram!("batch_proxy_adaptive_batch_size", "endpoint" => self.endpoint)
+            .record(state.batch_size as f64);
+        histogram!("batch_proxy_adaptive_waiting_time_seconds", "endpoint" => self.endpoint)
+            .record(state.waiting_time.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adaptive_batching() -> AdaptiveBatching {
+        AdaptiveBatching::new(
+            "test",
+            &AdaptiveBatchingSettings {
+                min_batch_size: 1,
+                min_waiting_time_ms: 1,
+                smoothing_factor: 1.0,
+            },
+            64,
+            20,
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_sparse_traffic_when_adapted_should_use_shortest_window() {
+        let adaptive_batching = adaptive_batching();
+
+        for _ in 0..3 {
+            adaptive_batching.record_arrival(1);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        adaptive_batching.record_latency(Duration::from_millis(30));
+
+        assert_eq!(adaptive_batching.waiting_time(), Duration::from_millis(1));
+        assert_eq!(adaptive_batching.batch_size(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_heavy_traffic_when_adapted_should_collect_inputs_arriving_during_upstream_call()
+    {
+        let adaptive_batching = adaptive_batching();
+
+        for _ in 0..3 {
+            adaptive_batching.record_arrival(1);
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        adaptive_batching.record_latency(Duration::from_millis(16));
+
+        assert_eq!(adaptive_batching.batch_size(), 16);
+        assert_eq!(adaptive_batching.waiting_time(), Duration::from_millis(16));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_two_workers_when_adapted_should_export_values_of_both() {
+        let recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
+        let metrics = recorder.handle();
+        let _recorder_guard = metrics::set_default_local_recorder(&recorder);
+        let heavy_traffic_worker = adaptive_batching();
+        let sparse_traffic_worker = adaptive_batching();
+
+        for _ in 0..2 {
+            heavy_traffic_worker.record_arrival(1);
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        heavy_traffic_worker.record_latency(Duration::from_millis(16));
+        for _ in 0..2 {
+            sparse_traffic_worker.record_arrival(1);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let rendered = metrics.render();
+        let exported = |series: &str| -> f64 {
+            rendered
+                .lines()
+                .find_map(|line| line.strip_prefix(series))
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap()
+        };
+
+        assert_eq!(
+            exported(r#"batch_proxy_adaptive_batch_size{endpoint="test",quantile="0"}"#),
+            sparse_traffic_worker.batch_size() as f64
+        );
+        assert!(
+            exported(r#"batch_proxy_adaptive_batch_size{endpoint="test",quantile="1"}"#)
+                >= heavy_traffic_worker.batch_size() as f64
+        );
+        assert_eq!(
+            exported(r#"batch_proxy_adaptive_batch_size_count{endpoint="test"}"#),
+            3.0
+        );
+    }
+}
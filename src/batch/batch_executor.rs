@@ -1,18 +1,24 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use anyhow::anyhow;
-use log::error;
+use log::{error, warn};
+use metrics::{counter, histogram};
+use tokio::time::Instant;
 
 use crate::{
     api::endpoint::{ApiEndpont, GroupingParams},
+    error::{ProxyError, ProxyResult},
     request::{RequestClient, RequestHandle},
 };
 
-use super::DataProvider;
+use super::{DataProvider, token_counter::estimate_input_length};
 
 pub struct Batch<TApiEndpoint: ApiEndpont> {
     clients: Vec<BatchedClient<TApiEndpoint>>,
     api_parameters: TApiEndpoint::ApiRequest,
+    /// Position in the sent inputs of every client input, if inputs were deduplicated
+    /// or sorted by length.
+    input_positions: Option<Vec<usize>>,
+    sent_input_count: usize,
 }
 
 struct BatchedClient<TApiEndpoint: ApiEndpont> {
@@ -21,9 +27,125 @@ struct BatchedClient<TApiEndpoint: ApiEndpont> {
 }
 
 impl<TApiEndpoint: ApiEndpont> Batch<TApiEndpoint> {
+    /// Builds the batch from client inputs in client order. Identical inputs are sent once,
+    /// and inputs sorted by length are sent in that order, so that the upstream pads them
+    /// to similar lengths.
+    fn new(
+        clients: Vec<BatchedClient<TApiEndpoint>>,
+        inputs: Vec<TApiEndpoint::DataItem>,
+        grouping_params: &TApiEndpoint::GroupingParams,
+        sort_by_length: bool,
+    ) -> Self {
+        let input_count = inputs.len();
+
+        let mut first_occurrences = HashMap::with_capacity(input_count);
+        let first_occurrence: Vec<usize> = inputs
+            .iter()
+            .enumerate()
+            .map(|(position, input)| *first_occurrences.entry(input).or_insert(position))
+            .collect();
+        drop(first_occurrences);
+
+        let mut unique_inputs: Vec<_> = inputs
+            .into_iter()
+            .enumerate()
+            .filter(|(position, _)| first_occurrence[*position] == *position)
+            .collect();
+        if sort_by_length {
+            unique_inputs.sort_by_key(|(_, input)| estimate_input_length(input));
+        }
+
+        let sent_input_count = unique_inputs.len();
+        let is_reordered = sent_input_count < input_count || sort_by_length;
+
+        let mut sent_positions = vec![0; input_count];
+        for (sent_position, (position, _)) in unique_inputs.iter().enumerate() {
+            sent_positions[*position] = sent_position;
+        }
+        let input_positions = is_reordered.then(|| {
+            first_occurrence
+                .iter()
+                .map(|first_position| sent_positions[*first_position])
+                .collect()
+        });
+
+        Batch {
+            clients,
+            api_parameters: grouping_params
+                .to_request(unique_inputs.into_iter().map(|(_, input)| input).collect()),
+            input_positions,
+            sent_input_count,
+        }
+    }
+
+    /// Maps items that follow the sent inputs back to every client input, cloning items of
+    /// deduplicated inputs.
+    fn restore_order<T: Clone>(&self, items: Vec<T>) -> Vec<T> {
+        let Some(input_positions) = &self.input_positions else {
+            return items;
+        };
+
+        let mut remaining_uses = vec![0usize; items.len()];
+        for sent_position in input_positions {
+            remaining_uses[*sent_position] += 1;
+        }
+
+        let mut items: Vec<_> = items.into_iter().map(Some).collect();
+        input_positions
+            .iter()
+            .map(|sent_position| {
+                remaining_uses[*sent_position] -= 1;
+                match remaining_uses[*sent_position] {
+                    0 => items[*sent_position].take(),
+                    _ => items[*sent_position].clone(),
+                }
+                .expect("Every sent item is used by at least one client input.")
+            })
+            .collect()
+    }
+
     pub fn api_parameters(&self) -> &TApiEndpoint::ApiRequest {
         &self.api_parameters
     }
+
+    /// Earliest deadline of the batched clients.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.clients
+            .iter()
+            .filter_map(|c| c.request_handle.deadline)
+            .min()
+    }
+
+    /// Splits the batch in two halves along client boundaries.
+    fn split(
+        self,
+        grouping_params: &TApiEndpoint::GroupingParams,
+        sort_by_length: bool,
+    ) -> (Batch<TApiEndpoint>, Batch<TApiEndpoint>) {
+        let Batch {
+            clients: mut left_clients,
+            api_parameters,
+            input_positions,
+            sent_input_count,
+        } = self;
+        let (sent_inputs, _) = TApiEndpoint::GroupingParams::decompose_api_request(api_parameters);
+        let mut left_inputs = Batch::<TApiEndpoint> {
+            clients: Vec::new(),
+            api_parameters: grouping_params.to_request(Vec::new()),
+            input_positions,
+            sent_input_count,
+        }
+        .restore_order(sent_inputs);
+
+        let right_clients = left_clients.split_off(left_clients.len() / 2);
+        let left_size = left_clients.iter().map(|c| c.request_size).sum();
+        let right_inputs = left_inputs.split_off(left_size);
+
+        (
+            Batch::new(left_clients, left_inputs, grouping_params, sort_by_length),
+            Batch::new(right_clients, right_inputs, grouping_params, sort_by_length),
+        )
+    }
 }
 
 pub async fn execute_batch<TApiEndpoint: ApiEndpont, TDataProvider: DataProvider<TApiEndpoint>>(
@@ -31,16 +153,68 @@ pub async fn execute_batch<TApiEndpoint: ApiEndpont, TDataProvider: DataProvider
     grouping_params: Arc<TApiEndpoint::GroupingParams>,
     request_clients: Vec<RequestClient<TApiEndpoint>>,
     current_batch_size: usize,
+    sort_by_length: bool,
 ) {
-    let batch = batch_requests(current_batch_size, request_clients, &grouping_params);
+    let batch = batch_requests(
+        current_batch_size,
+        request_clients,
+        &grouping_params,
+        sort_by_length,
+    );
+
+    if current_batch_size > 0 {
+        let duplicate_count = current_batch_size - batch.sent_input_count;
+        histogram!("batch_proxy_batch_dedup_ratio", "endpoint" => TApiEndpoint::NAME)
+            .record(duplicate_count as f64 / current_batch_size as f64);
+    }
+
+    execute_or_bisect(&*data_provider, &grouping_params, sort_by_length, batch).await;
+}
+
+/// Executes the batch. If the upstream rejects it because of its inputs, the batch is split
+/// and each half is retried, until the clients with invalid inputs are isolated.
+async fn execute_or_bisect<TApiEndpoint, TDataProvider>(
+    data_provider: &TDataProvider,
+    grouping_params: &TApiEndpoint::GroupingParams,
+    sort_by_length: bool,
+    batch: Batch<TApiEndpoint>,
+) where
+    TApiEndpoint: ApiEndpont,
+    TDataProvider: DataProvider<TApiEndpoint>,
+{
     let data = data_provider.get_data_for_batch(&batch).await;
-    distribute_response(data, batch);
+
+    match data {
+        Err(err) if err.is_input_error() && batch.clients.len() > 1 => {
+            warn!(
+                "Batch was rejected because of its inputs, splitting it to isolate invalid requests. [clients = {}, error = {err}]",
+                batch.clients.len()
+            );
+
+            // Halves run one after the other, so that the batch holds a single in-flight slot.
+            let (left, right) = batch.split(grouping_params, sort_by_length);
+            for half in [left, right] {
+                Box::pin(execute_or_bisect(
+                    data_provider,
+                    grouping_params,
+                    sort_by_length,
+                    half,
+                ))
+                .await;
+            }
+        }
+        data => {
+            let data = data.and_then(|data| validate_response(data, &batch));
+            distribute_response(data, batch)
+        }
+    }
 }
 
 fn batch_requests<TApiEndpoint>(
     current_batch_size: usize,
     request_clients: Vec<RequestClient<TApiEndpoint>>,
     grouping_params: &TApiEndpoint::GroupingParams,
+    sort_by_length: bool,
 ) -> Batch<TApiEndpoint>
 where
     TApiEndpoint: ApiEndpont,
@@ -59,26 +233,49 @@ where
         });
     }
 
-    let api_parameters = grouping_params.to_request(inputs);
+    Batch::new(clients, inputs, grouping_params, sort_by_length)
+}
+
+/// Checks that the response can be distributed between batched clients.
+fn validate_response<TApiEndpoint: ApiEndpont>(
+    response: Vec<TApiEndpoint::ApiResponseItem>,
+    batch: &Batch<TApiEndpoint>,
+) -> ProxyResult<Vec<TApiEndpoint::ApiResponseItem>> {
+    let expected_size = batch.sent_input_count;
 
-    Batch {
-        clients,
-        api_parameters,
-    }
+    let validation_result = if response.len() != expected_size {
+        Err(format!(
+            "Expected {expected_size} results, got {}.",
+            response.len()
+        ))
+    } else {
+        TApiEndpoint::validate_response(&response)
+    };
+
+    validation_result.map(|_| response).map_err(|message| {
+        error!(
+            "Response integrity check failed. [endpoint = {}, error = {message}]",
+            TApiEndpoint::NAME
+        );
+        counter!("batch_proxy_response_integrity_errors_total", "endpoint" => TApiEndpoint::NAME)
+            .increment(1);
+
+        ProxyError::Integrity(message)
+    })
 }
 
 fn distribute_response<TApiEndpoint: ApiEndpont>(
-    response: anyhow::Result<Vec<TApiEndpoint::ApiResponseItem>>,
+    response: ProxyResult<Vec<TApiEndpoint::ApiResponseItem>>,
     batch: Batch<TApiEndpoint>,
 ) {
-    let batched_clients = batch.clients;
     match response {
         Ok(result) => {
+            let result = batch.restore_order(result);
             let mut result_iterator = result.into_iter();
             for BatchedClient {
                 request_size,
                 request_handle,
-            } in batched_clients
+            } in batch.clients
             {
                 let client_data: Vec<_> = result_iterator.by_ref().take(request_size).collect();
                 request_handle.reply_with_result(client_data);
@@ -87,9 +284,240 @@ fn distribute_response<TApiEndpoint: ApiEndpont>(
 
         Err(err) => {
             error!("Embedding API call failed. Error = {0}", &err);
-            for BatchedClient { request_handle, .. } in batched_clients {
-                request_handle.reply_with_error(anyhow!("API call failed. Please try again"));
+            for BatchedClient { request_handle, .. } in batch.clients {
+                request_handle.reply_with_error(err.clone());
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use async_trait::async_trait;
+    use uuid::Uuid;
+
+    use crate::{
+        batch::test_utils::{TestApiEndpoint, TestGroupingParams},
+        request::RequestOptions,
+    };
+
+    use super::*;
+
+    /// Rejects every batch that contains zero, echoes inputs back otherwise.
+    #[derive(Default)]
+    struct PoisonedInputDataProvider {
+        calls: AtomicUsize,
+        sent_inputs: Mutex<Vec<Vec<u32>>>,
+    }
+
+    #[async_trait]
+    impl DataProvider<TestApiEndpoint> for PoisonedInputDataProvider {
+        async fn get_data_for_batch(
+            &self,
+            batch: &Batch<TestApiEndpoint>,
+        ) -> ProxyResult<Vec<u32>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let (_, inputs) = batch.api_parameters();
+            self.sent_inputs.lock().unwrap().push(inputs.clone());
+
+            if inputs.contains(&0) {
+                return Err(ProxyError::Upstream {
+                    status: 422,
+                    message: "Invalid input".to_string(),
+                    error_type: Some("validation".to_string()),
+                });
+            }
+
+            Ok(inputs.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn given_invalid_input_when_batch_rejected_should_fail_only_its_client() {
+        let data_provider = Arc::new(PoisonedInputDataProvider::default());
+        let inputs = [vec![1], vec![2, 3], vec![0], vec![4, 5]];
+        let (receivers, clients): (Vec<_>, Vec<_>) = inputs
+            .into_iter()
+            .map(|data| RequestClient::new(data, Uuid::new_v4(), RequestOptions::default()))
+            .unzip();
+
+        execute_batch(
+            Arc::clone(&data_provider),
+            Arc::new(TestGroupingParams(0)),
+            clients,
+            6,
+            false,
+        )
+        .await;
+
+        let mut results = Vec::new();
+        for receiver in receivers {
+            results.push(receiver.await.unwrap());
+        }
+
+        assert_eq!(results[0].as_ref().unwrap(), &vec![1]);
+        assert_eq!(results[1].as_ref().unwrap(), &vec![2, 3]);
+        assert!(matches!(
+            results[2],
+            Err(ProxyError::Upstream { status: 422, .. })
+        ));
+        assert_eq!(results[3].as_ref().unwrap(), &vec![4, 5]);
+        assert_eq!(data_provider.calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn given_inputs_sorted_by_length_when_distributed_should_map_results_to_their_clients() {
+        let data_provider = Arc::new(PoisonedInputDataProvider::default());
+        let inputs = [vec![5], vec![3, 1], vec![0], vec![4, 2]];
+        let (receivers, clients): (Vec<_>, Vec<_>) = inputs
+            .into_iter()
+            .map(|data| RequestClient::new(data, Uuid::new_v4(), RequestOptions::default()))
+            .unzip();
+
+        execute_batch(
+            Arc::clone(&data_provider),
+            Arc::new(TestGroupingParams(0)),
+            clients,
+            6,
+            true,
+        )
+        .await;
+
+        let mut results = Vec::new();
+        for receiver in receivers {
+            results.push(receiver.await.unwrap());
+        }
+
+        assert_eq!(
+            data_provider.sent_inputs.lock().unwrap()[0],
+            vec![0, 1, 2, 3, 4, 5]
+        );
+        assert_eq!(results[0].as_ref().unwrap(), &vec![5]);
+        assert_eq!(results[1].as_ref().unwrap(), &vec![3, 1]);
+        assert!(results[2].is_err());
+        assert_eq!(results[3].as_ref().unwrap(), &vec![4, 2]);
+    }
+
+    #[tokio::test]
+    async fn given_identical_inputs_when_batched_should_send_them_once_and_fan_out_results() {
+        let data_provider = Arc::new(PoisonedInputDataProvider::default());
+        let inputs = [vec![1, 2], vec![2], vec![1, 3, 1]];
+        let (receivers, clients): (Vec<_>, Vec<_>) = inputs
+            .into_iter()
+            .map(|data| RequestClient::new(data, Uuid::new_v4(), RequestOptions::default()))
+            .unzip();
+
+        execute_batch(
+            Arc::clone(&data_provider),
+            Arc::new(TestGroupingParams(0)),
+            clients,
+            6,
+            false,
+        )
+        .await;
+
+        let mut results = Vec::new();
+        for receiver in receivers {
+            results.push(receiver.await.unwrap().unwrap());
+        }
+
+        assert_eq!(data_provider.sent_inputs.lock().unwrap()[0], vec![1, 2, 3]);
+        assert_eq!(results, vec![vec![1, 2], vec![2], vec![1, 3, 1]]);
+    }
+
+    #[tokio::test]
+    async fn given_upstream_failure_when_batch_rejected_should_not_split_batch() {
+        struct FailingDataProvider {
+            calls: AtomicUsize,
+            status: u16,
+            error_type: &'static str,
+        }
+
+        #[async_trait]
+        impl DataProvider<TestApiEndpoint> for FailingDataProvider {
+            async fn get_data_for_batch(
+                &self,
+                _batch: &Batch<TestApiEndpoint>,
+            ) -> ProxyResult<Vec<u32>> {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+
+                Err(ProxyError::Upstream {
+                    status: self.status,
+                    message: "Upstream failed".to_string(),
+                    error_type: Some(self.error_type.to_string()),
+                })
+            }
+        }
+
+        for (status, error_type) in [(429, "overloaded"), (424, "backend")] {
+            let (first, first_client) =
+                RequestClient::new(vec![1], Uuid::new_v4(), RequestOptions::default());
+            let (second, second_client) =
+                RequestClient::new(vec![2], Uuid::new_v4(), RequestOptions::default());
+
+            let data_provider = Arc::new(FailingDataProvider {
+                calls: AtomicUsize::new(0),
+                status,
+                error_type,
+            });
+
+            execute_batch(
+                Arc::clone(&data_provider),
+                Arc::new(TestGroupingParams(0)),
+                vec![first_client, second_client],
+                2,
+                false,
+            )
+            .await;
+
+            assert!(first.await.unwrap().is_err());
+            assert!(second.await.unwrap().is_err());
+            assert_eq!(data_provider.calls.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn given_short_response_when_distributed_should_fail_every_client() {
+        struct ShortResponseDataProvider;
+
+        #[async_trait]
+        impl DataProvider<TestApiEndpoint> for ShortResponseDataProvider {
+            async fn get_data_for_batch(
+                &self,
+                batch: &Batch<TestApiEndpoint>,
+            ) -> ProxyResult<Vec<u32>> {
+                let (_, inputs) = batch.api_parameters();
+
+                Ok(inputs[1..].to_vec())
+            }
+        }
+
+        let (first, first_client) =
+            RequestClient::new(vec![1, 2], Uuid::new_v4(), RequestOptions::default());
+        let (second, second_client) =
+            RequestClient::new(vec![3], Uuid::new_v4(), RequestOptions::default());
+
+        execute_batch(
+            Arc::new(ShortResponseDataProvider),
+            Arc::new(TestGroupingParams(0)),
+            vec![first_client, second_client],
+            3,
+            false,
+        )
+        .await;
+
+        assert!(matches!(
+            first.await.unwrap(),
+            Err(ProxyError::Integrity(_))
+        ));
+        assert!(matches!(
+            second.await.unwrap(),
+            Err(ProxyError::Integrity(_))
+        ));
+    }
+}
@@ -1,20 +1,40 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use log::info;
-use tokio::sync::mpsc;
+use log::{info, warn};
+use tokio::{
+    sync::{Semaphore, mpsc},
+    time::Instant,
+};
 use uuid::Uuid;
 
 use crate::{
-    api::endpoint::ApiEndpont, api::endpoint::GroupingParams, request::RequestClient,
-    settings::BatchSettings,
+    api::endpoint::ApiEndpont,
+    api::endpoint::GroupingParams,
+    cache::{
+        disk_cache::{DiskCache, DiskCacheStore},
+        memory_cache::MemoryCache,
+    },
+    error::{ProxyError, ProxyResult},
+    request::{RequestClient, RequestOptions},
+    settings::{BatchSettings, CacheSettings},
+};
+
+use super::{
+    DataProvider,
+    batch_worker::BatchWorkerHandle,
+    token_counter::{TokenCounter, count_input_tokens},
 };
 
-use super::{DataProvider, batch_worker::BatchWorkerHandle};
+struct ManagedWorker<TApiEndpoint: ApiEndpont> {
+    handle: BatchWorkerHandle<TApiEndpoint>,
+    last_request_at: Instant,
+}
 
 struct BatchManager<TApiEndpoint: ApiEndpont, TDataProvider: DataProvider<TApiEndpoint>> {
-    workers: HashMap<TApiEndpoint::GroupingParams, BatchWorkerHandle<TApiEndpoint>>,
+    workers: HashMap<TApiEndpoint::GroupingParams, ManagedWorker<TApiEndpoint>>,
     data_provider: Arc<TDataProvider>,
     batch_config: BatchSettings,
+    in_flight_batches: Arc<Semaphore>,
 }
 
 impl<TApiEndpoint: ApiEndpont, TExecutor: DataProvider<TApiEndpoint>>
@@ -23,17 +43,52 @@ impl<TApiEndpoint: ApiEndpont, TExecutor: DataProvider<TApiEndpoint>>
     fn handle_messages(&mut self, message: BatchManagerMessage<TApiEndpoint>) {
         match message {
             BatchManagerMessage::NewRequest(req, grouping_params) => {
+                if !self.workers.contains_key(&grouping_params)
+                    && self.workers.len() >= self.batch_config.max_workers
+                {
+                    warn!(
+                        "Maximum number of workers reached, rejecting request. [parameters = {grouping_params:#?}, client_id = {}]",
+                        req.handle.client_id
+                    );
+                    req.handle.reply_with_error(ProxyError::Overloaded(
+                        "Too many distinct request parameter combinations are being processed. Please try again later.".to_string()
+                    ));
+                    return;
+                }
+
                 let worker = self.workers.entry(grouping_params).or_insert_with_key(|grouping_params| {
                     let worker_id = Uuid::new_v4();
 
-                    info!("Starting new worker. [parameters = {grouping_params:#?}, worker_id = {worker_id}");
-                    super::batch_worker::start(Arc::new(grouping_params.clone()), &self.batch_config, worker_id, Arc::clone(&self.data_provider))
+                    let worker_config = self.batch_config.for_group(TApiEndpoint::NAME, grouping_params);
+
+                    info!("Starting new worker. [parameters = {grouping_params:#?}, worker_id = {worker_id}, max_batch_size = {}, max_waiting_time_ms = {}]", worker_config.max_batch_size, worker_config.max_waiting_time_ms);
+                    ManagedWorker {
+                        handle: super::batch_worker::start(Arc::new(grouping_params.clone()), &worker_config, worker_id, Arc::clone(&self.data_provider), Arc::clone(&self.in_flight_batches)),
+                        last_request_at: Instant::now(),
+                    }
                 });
 
-                worker.put_request(req);
+                worker.last_request_at = Instant::now();
+                worker.handle.put_request(req);
             }
         }
     }
+
+    /// Removes workers that did not receive requests for longer than the configured idle timeout.
+    /// Dropping the handle closes the worker channel, so the worker flushes what it has left and stops.
+    fn evict_idle_workers(&mut self) {
+        let idle_timeout = Duration::from_millis(self.batch_config.worker_idle_timeout_ms);
+
+        self.workers.retain(|grouping_params, worker| {
+            let is_active = worker.last_request_at.elapsed() < idle_timeout;
+
+            if !is_active {
+                info!("Stopping idle worker. [parameters = {grouping_params:#?}]");
+            }
+
+            is_active
+        });
+    }
 }
 
 enum BatchManagerMessage<TApiEndpoint: ApiEndpont> {
@@ -42,16 +97,178 @@ enum BatchManagerMessage<TApiEndpoint: ApiEndpont> {
 
 pub struct BatchManagerHandle<TApiEndpoint: ApiEndpont> {
     sender: mpsc::UnboundedSender<BatchManagerMessage<TApiEndpoint>>,
+    /// Measures requests when batches are limited by a token budget.
+    token_counter: Option<Arc<dyn TokenCounter>>,
+    /// Results of recently seen inputs, only missing inputs are sent to the worker.
+    memory_cache: Option<MemoryCache<TApiEndpoint>>,
+    /// Results that survive restarts, checked for inputs missing from the memory cache.
+    disk_cache: Option<DiskCache<TApiEndpoint>>,
+}
+
+/// Fills results missing from `results`, in order, with results of the missing inputs.
+fn fill_missing<T>(results: Vec<Option<T>>, missing_results: Vec<Option<T>>) -> Vec<Option<T>> {
+    let mut missing_results = missing_results.into_iter();
+
+    results
+        .into_iter()
+        .map(|result| result.or_else(|| missing_results.next().flatten()))
+        .collect()
+}
+
+/// Runs the call until the deadline, if the client set one.
+async fn with_deadline<T>(
+    deadline: Option<Instant>,
+    call: impl Future<Output = ProxyResult<T>>,
+) -> ProxyResult<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, call)
+            .await
+            .map_err(|_| ProxyError::DeadlineExceeded)?,
+        None => call.await,
+    }
 }
 
 impl<TApiEndpoint: ApiEndpont> BatchManagerHandle<TApiEndpoint> {
     pub async fn call_api(
         &self,
         api_request: TApiEndpoint::ApiRequest,
-    ) -> anyhow::Result<Vec<TApiEndpoint::ApiResponseItem>> {
+        options: RequestOptions,
+    ) -> ProxyResult<Vec<TApiEndpoint::ApiResponseItem>> {
         let (data, grouping_params) =
             TApiEndpoint::GroupingParams::decompose_api_request(api_request);
 
+        with_deadline(
+            options.deadline,
+            self.call(data, grouping_params, None, options),
+        )
+        .await
+    }
+
+    /// Like `call_api`, also returns token counts of the inputs if batches are limited by
+    /// a token budget. Inputs are counted once, for both batching and the caller.
+    pub async fn call_api_counting_tokens(
+        &self,
+        api_request: TApiEndpoint::ApiRequest,
+        options: RequestOptions,
+    ) -> ProxyResult<(Vec<TApiEndpoint::ApiResponseItem>, Option<Vec<usize>>)> {
+        let (data, grouping_params) =
+            TApiEndpoint::GroupingParams::decompose_api_request(api_request);
+
+        with_deadline(options.deadline, async {
+            let token_counts = match &self.token_counter {
+                Some(token_counter) => Some(
+                    count_input_tokens::<TApiEndpoint>(
+                        token_counter.as_ref(),
+                        &grouping_params,
+                        &data,
+                    )
+                    .await,
+                ),
+                None => None,
+            };
+
+            let results = self
+                .call(data, grouping_params, token_counts.clone(), options)
+                .await?;
+
+            Ok((results, token_counts))
+        })
+        .await
+    }
+
+    /// Serves cached inputs from the caches and sends the rest to the worker.
+    /// `token_counts` of the inputs are reused if they were already counted.
+    async fn call(
+        &self,
+        data: Vec<TApiEndpoint::DataItem>,
+        grouping_params: TApiEndpoint::GroupingParams,
+        token_counts: Option<Vec<usize>>,
+        options: RequestOptions,
+    ) -> ProxyResult<Vec<TApiEndpoint::ApiResponseItem>> {
+        if self.memory_cache.is_none() && self.disk_cache.is_none() {
+            return self
+                .call_worker(data, grouping_params, token_counts, options)
+                .await;
+        }
+
+        let mut cached = match &self.memory_cache {
+            Some(memory_cache) => memory_cache.get_many(&grouping_params, &data),
+            None => vec![None; data.len()],
+        };
+
+        if let Some(disk_cache) = &self.disk_cache {
+            let memory_misses: Vec<_> = data
+                .iter()
+                .zip(&cached)
+                .filter(|(_, cached)| cached.is_none())
+                .map(|(input, _)| input)
+                .collect();
+
+            if !memory_misses.is_empty() {
+                let disk_cached = disk_cache.get_many(&grouping_params, &memory_misses).await;
+
+                if let Some(memory_cache) = &self.memory_cache {
+                    let (inputs, items): (Vec<_>, Vec<_>) = memory_misses
+                        .into_iter()
+                        .zip(&disk_cached)
+                        .filter_map(|(input, item)| Some((input.clone(), item.clone()?)))
+                        .unzip();
+                    memory_cache.insert_many(&grouping_params, inputs, &items);
+                }
+
+                cached = fill_missing(cached, disk_cached);
+            }
+        }
+
+        let missing_token_counts = token_counts.map(|token_counts| {
+            token_counts
+                .into_iter()
+                .zip(&cached)
+                .filter(|(_, cached)| cached.is_none())
+                .map(|(token_count, _)| token_count)
+                .collect()
+        });
+        let missing_data: Vec<_> = data
+            .into_iter()
+            .zip(&cached)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(input, _)| input)
+            .collect();
+
+        if missing_data.is_empty() {
+            return Ok(cached.into_iter().flatten().collect());
+        }
+
+        let fresh = self
+            .call_worker(
+                missing_data.clone(),
+                grouping_params.clone(),
+                missing_token_counts,
+                options,
+            )
+            .await?;
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.insert_many(&grouping_params, &missing_data, &fresh);
+        }
+        if let Some(memory_cache) = &self.memory_cache {
+            memory_cache.insert_many(&grouping_params, missing_data, &fresh);
+        }
+
+        Ok(fill_missing(cached, fresh.into_iter().map(Some).collect())
+            .into_iter()
+            .map(|result| result.expect("Worker returns a result for every missing input."))
+            .collect())
+    }
+
+    /// Sends the request to the worker. Callers limit it by the deadline, dropping the receiver
+    /// lets the worker discard the request if it is still queued.
+    async fn call_worker(
+        &self,
+        data: Vec<TApiEndpoint::DataItem>,
+        grouping_params: TApiEndpoint::GroupingParams,
+        token_counts: Option<Vec<usize>>,
+        options: RequestOptions,
+    ) -> ProxyResult<Vec<TApiEndpoint::ApiResponseItem>> {
         let client_id = Uuid::new_v4();
 
         info!(
@@ -59,31 +276,299 @@ impl<TApiEndpoint: ApiEndpont> BatchManagerHandle<TApiEndpoint> {
             data, grouping_params, client_id
         );
 
-        let (receiver, client) = RequestClient::new(data, client_id);
+        let token_counts = match (token_counts, &self.token_counter) {
+            (Some(token_counts), _) => token_counts,
+            (None, Some(token_counter)) => {
+                count_input_tokens::<TApiEndpoint>(token_counter.as_ref(), &grouping_params, &data)
+                    .await
+            }
+            (None, None) => Vec::new(),
+        };
+
+        let (receiver, mut client) = RequestClient::new(data, client_id, options);
+        client.token_counts = token_counts;
 
         self.sender
-            .send(BatchManagerMessage::NewRequest(client, grouping_params))?;
+            .send(BatchManagerMessage::NewRequest(client, grouping_params))
+            .map_err(|_| ProxyError::Internal("Batch manager has stopped.".to_string()))?;
 
-        receiver.await?
+        receiver.await.map_err(|_| {
+            ProxyError::Internal("Request was dropped before completion.".to_string())
+        })?
     }
 }
 
+/// Starts a batch manager. In-flight batches semaphore is shared between managers to limit
+/// the total number of batches being executed at the same time.
 pub fn start<TApiEndpoint: ApiEndpont>(
     data_provider: Arc<impl DataProvider<TApiEndpoint>>,
     batch_config: BatchSettings,
+    in_flight_batches: Arc<Semaphore>,
+    token_counter: Option<Arc<dyn TokenCounter>>,
+    cache_settings: &CacheSettings,
+    disk_cache_store: Option<Arc<DiskCacheStore>>,
 ) -> BatchManagerHandle<TApiEndpoint> {
     let (sender, mut receiver) = mpsc::unbounded_channel::<BatchManagerMessage<TApiEndpoint>>();
+    // Interval panics on a zero period, an idle timeout of zero evicts workers every millisecond.
+    let mut eviction_interval = tokio::time::interval(Duration::from_millis(
+        batch_config.worker_idle_timeout_ms.max(1),
+    ));
     let mut manager = BatchManager {
         workers: HashMap::new(),
         data_provider,
         batch_config,
+        in_flight_batches,
     };
 
     tokio::spawn(async move {
-        while let Some(msg) = receiver.recv().await {
-            manager.handle_messages(msg);
+        loop {
+            tokio::select! {
+                msg = receiver.recv() => match msg {
+                    Some(msg) => manager.handle_messages(msg),
+                    None => break,
+                },
+                _ = eviction_interval.tick() => manager.evict_idle_workers(),
+            }
         }
     });
 
-    BatchManagerHandle { sender }
+    BatchManagerHandle {
+        sender,
+        token_counter,
+        memory_cache: cache_settings.memory.as_ref().map(MemoryCache::new),
+        disk_cache: disk_cache_store.map(DiskCache::new),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use serde_json::json;
+
+    use crate::{
+        api::endpoint::embed_endpoint::EmbedApiEndpoint,
+        batch::test_utils::{
+            RecordingDataProvider, TestApiEndpoint, TestGroupingParams, batch_settings,
+        },
+        settings::MemoryCacheSettings,
+    };
+
+    use super::*;
+
+    fn manager(
+        max_workers: usize,
+        worker_idle_timeout_ms: u64,
+    ) -> BatchManager<TestApiEndpoint, RecordingDataProvider> {
+        let (data_provider, _) = RecordingDataProvider::new();
+
+        BatchManager {
+            workers: HashMap::new(),
+            data_provider: Arc::new(data_provider),
+            batch_config: BatchSettings {
+                max_workers,
+                worker_idle_timeout_ms,
+                ..batch_settings(32, 10)
+            },
+            in_flight_batches: Arc::new(Semaphore::new(64)),
+        }
+    }
+
+    fn request(
+        manager: &mut BatchManager<TestApiEndpoint, RecordingDataProvider>,
+        group: u32,
+    ) -> tokio::sync::oneshot::Receiver<ProxyResult<Vec<u32>>> {
+        let (receiver, client) =
+            RequestClient::new(vec![group], Uuid::new_v4(), RequestOptions::default());
+        manager.handle_messages(BatchManagerMessage::NewRequest(
+            client,
+            TestGroupingParams(group),
+        ));
+
+        receiver
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_idle_worker_when_timeout_passed_should_evict_it() {
+        let mut manager = manager(16, 100);
+        let _idle = request(&mut manager, 1);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let _active = request(&mut manager, 2);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        manager.evict_idle_workers();
+
+        assert_eq!(manager.workers.len(), 1);
+        assert!(manager.workers.contains_key(&TestGroupingParams(2)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_worker_limit_reached_when_new_parameters_arrive_should_reject_request() {
+        let mut manager = manager(1, 100);
+        let accepted = request(&mut manager, 1);
+        let rejected = request(&mut manager, 2);
+
+        assert!(rejected.await.unwrap().is_err());
+        assert_eq!(accepted.await.unwrap().unwrap(), vec![1]);
+        assert_eq!(manager.workers.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_zero_idle_timeout_when_started_should_serve_requests() {
+        let (data_provider, _) = RecordingDataProvider::new();
+        let handle = start::<TestApiEndpoint>(
+            Arc::new(data_provider),
+            BatchSettings {
+                worker_idle_timeout_ms: 0,
+                ..batch_settings(32, 10)
+            },
+            Arc::new(Semaphore::new(64)),
+            None,
+            &CacheSettings::default(),
+            None,
+        );
+
+        let result = handle
+            .call_api((0, vec![1]), RequestOptions::default())
+            .await;
+
+        assert_eq!(result.unwrap(), vec![1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_deadline_when_passed_while_queued_should_fail_with_deadline_exceeded() {
+        let (data_provider, mut flushes) = RecordingDataProvider::new();
+        let handle = start::<TestApiEndpoint>(
+            Arc::new(data_provider),
+            batch_settings(32, 50),
+            Arc::new(Semaphore::new(64)),
+            None,
+            &CacheSettings::default(),
+            None,
+        );
+
+        let result = handle
+            .call_api(
+                (0, vec![1]),
+                RequestOptions {
+                    deadline: Some(Instant::now() + Duration::from_millis(10)),
+                    ..Default::default()
+                },
+            )
+            .await;
+
+        assert!(matches!(result, Err(ProxyError::DeadlineExceeded)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(flushes.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_deadline_when_passed_while_counting_tokens_should_fail_with_deadline_exceeded() {
+        struct SlowTokenCounter;
+
+        #[async_trait]
+        impl TokenCounter for SlowTokenCounter {
+            async fn count_tokens(&self, texts: &[&str]) -> ProxyResult<Vec<usize>> {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                Ok(vec![1; texts.len()])
+            }
+        }
+
+        let (data_provider, mut flushes) = RecordingDataProvider::new();
+        let handle = start::<EmbedApiEndpoint>(
+            Arc::new(data_provider),
+            batch_settings(32, 10),
+            Arc::new(Semaphore::new(64)),
+            Some(Arc::new(SlowTokenCounter)),
+            &CacheSettings::default(),
+            None,
+        );
+        let started_at = Instant::now();
+
+        let result = handle
+            .call_api_counting_tokens(
+                serde_json::from_value(json!({ "inputs": "what is the capital of France" }))
+                    .unwrap(),
+                RequestOptions {
+                    deadline: Some(started_at + Duration::from_millis(10)),
+                    ..Default::default()
+                },
+            )
+            .await;
+
+        assert!(matches!(result, Err(ProxyError::DeadlineExceeded)));
+        assert_eq!(started_at.elapsed(), Duration::from_millis(10));
+        assert!(flushes.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_token_budget_when_counting_tokens_should_count_inputs_once() {
+        #[derive(Default)]
+        struct CountingTokenCounter {
+            calls: AtomicUsize,
+        }
+
+        #[async_trait]
+        impl TokenCounter for CountingTokenCounter {
+            async fn count_tokens(&self, texts: &[&str]) -> ProxyResult<Vec<usize>> {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                Ok(vec![2; texts.len()])
+            }
+        }
+
+        let token_counter = Arc::new(CountingTokenCounter::default());
+        let (data_provider, _) = RecordingDataProvider::new();
+        let handle = start::<EmbedApiEndpoint>(
+            Arc::new(data_provider),
+            batch_settings(32, 10),
+            Arc::new(Semaphore::new(64)),
+            Some(Arc::clone(&token_counter) as Arc<dyn TokenCounter>),
+            &CacheSettings::default(),
+            None,
+        );
+
+        let (results, token_counts) = handle
+            .call_api_counting_tokens(
+                serde_json::from_value(json!({ "inputs": ["a", [1, 2, 3]] })).unwrap(),
+                RequestOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(token_counts, Some(vec![2, 3]));
+        assert_eq!(token_counter.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_partially_cached_request_when_called_should_send_only_missing_inputs() {
+        let (data_provider, mut flushes) = RecordingDataProvider::new();
+        let handle = start::<TestApiEndpoint>(
+            Arc::new(data_provider),
+            batch_settings(32, 10),
+            Arc::new(Semaphore::new(64)),
+            None,
+            &CacheSettings {
+                memory: Some(MemoryCacheSettings {
+                    max_size_bytes: 1024,
+                    ttl_ms: None,
+                }),
+                disk: None,
+            },
+            None,
+        );
+
+        let first = handle
+            .call_api((0, vec![1, 2]), RequestOptions::default())
+            .await;
+        let second = handle
+            .call_api((0, vec![3, 2, 1, 4]), RequestOptions::default())
+            .await;
+
+        assert_eq!(first.unwrap(), vec![1, 2]);
+        assert_eq!(second.unwrap(), vec![3, 2, 1, 4]);
+        assert_eq!(flushes.recv().await.unwrap().1, 2);
+        assert_eq!(flushes.recv().await.unwrap().1, 2);
+    }
 }
@@ -1,37 +1,126 @@
 use std::{sync::Arc, time::Duration};
 
-use anyhow::anyhow;
-use log::{error, info};
-use tokio::sync::mpsc;
+use log::{debug, error, info, warn};
+use metrics::counter;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc, watch},
+    time::Instant,
+};
 use uuid::Uuid;
 
 use crate::{
-    api::endpoint::ApiEndpont, batch::batch_executor, request::RequestClient,
-    settings::BatchSettings,
+    api::endpoint::ApiEndpont,
+    batch::batch_executor,
+    error::ProxyError,
+    request::{Priority, RequestClient},
+    settings::{BatchSettings, LengthSortingSettings},
 };
 
-use super::{DataProvider, request_store::RequestStore};
+use super::{
+    DataProvider,
+    adaptive_batching::AdaptiveBatching,
+    request_store::{BatchBuilder, RequestStore},
+};
 
 enum BatchWorkerMessage<TApiEndpoint: ApiEndpont> {
     NewRequest(RequestClient<TApiEndpoint>),
 }
 
-pub struct BatchWorker<TApiEndpoint: ApiEndpont, TDataProvider: DataProvider<TApiEndpoint>> {
+/// Limits on batches that are being executed at the same time, for this worker and across all workers.
+#[derive(Clone)]
+struct InFlightLimits {
+    worker: Arc<Semaphore>,
+    global: Arc<Semaphore>,
+}
+
+/// Permits are held by the executing batch and released once it completes.
+struct InFlightPermits {
+    _worker: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+impl InFlightLimits {
+    async fn acquire(&self) -> InFlightPermits {
+        let worker = Arc::clone(&self.worker)
+            .acquire_owned()
+            .await
+            .expect("In-flight semaphore is never closed.");
+        let global = Arc::clone(&self.global)
+            .acquire_owned()
+            .await
+            .expect("In-flight semaphore is never closed.");
+
+        InFlightPermits {
+            _worker: worker,
+            _global: global,
+        }
+    }
+}
+
+/// Requests of a single priority class, received through their own channel so that
+/// a full low priority queue does not hold back high priority requests.
+struct PriorityQueue<TApiEndpoint: ApiEndpont> {
     request_store: RequestStore<TApiEndpoint>,
-    data_provider: Arc<TDataProvider>,
     receiver: mpsc::Receiver<BatchWorkerMessage<TApiEndpoint>>,
+    /// How long the oldest request may wait before the batch is flushed.
+    max_waiting_time: Duration,
+    is_closed: bool,
+}
+
+impl<TApiEndpoint: ApiEndpont> PriorityQueue<TApiEndpoint> {
+    fn new(
+        receiver: mpsc::Receiver<BatchWorkerMessage<TApiEndpoint>>,
+        max_waiting_time_ms: u64,
+    ) -> Self {
+        Self {
+            request_store: RequestStore::new(),
+            receiver,
+            max_waiting_time: Duration::from_millis(max_waiting_time_ms),
+            is_closed: false,
+        }
+    }
+
+    fn flush_deadline(&self) -> Option<Instant> {
+        self.request_store
+            .oldest_request_at()
+            .map(|oldest_request_at| oldest_request_at + self.max_waiting_time)
+    }
+}
+
+pub struct BatchWorker<TApiEndpoint: ApiEndpont, TDataProvider: DataProvider<TApiEndpoint>> {
+    high_priority: PriorityQueue<TApiEndpoint>,
+    low_priority: PriorityQueue<TApiEndpoint>,
+    max_batch_size: usize,
+    /// Configured batch size, the most inputs the upstream accepts in one call.
+    /// Unlike `max_batch_size`, it is not adjusted to the load.
+    batch_size_limit: usize,
+    max_batch_tokens: Option<usize>,
+    /// Holds back requests of dissimilar length and sorts batch inputs by length, if enabled.
+    length_sorting: Option<LengthSortingSettings>,
+    /// Adjusts `max_batch_size` and the high priority window to the load, if enabled.
+    adaptive_batching: Option<Arc<AdaptiveBatching>>,
+    /// Low priority requests that waited this long go into the next batch before high priority ones.
+    low_priority_starvation_time: Duration,
+    in_flight_limits: InFlightLimits,
+    /// `false` while the data provider can not serve batches.
+    availability: watch::Receiver<bool>,
+    data_provider: Arc<TDataProvider>,
     worker_id: Uuid,
     grouping_params: Arc<TApiEndpoint::GroupingParams>,
 }
 
 pub struct BatchWorkerHandle<TApiEndpoint: ApiEndpont> {
-    sender: mpsc::Sender<BatchWorkerMessage<TApiEndpoint>>,
+    high_priority_sender: mpsc::Sender<BatchWorkerMessage<TApiEndpoint>>,
+    low_priority_sender: mpsc::Sender<BatchWorkerMessage<TApiEndpoint>>,
     worker_id: Uuid,
 }
 
 impl<TApiEndpoint: ApiEndpont> BatchWorkerHandle<TApiEndpoint> {
     pub fn put_request(&self, req: RequestClient<TApiEndpoint>) {
-        let sender = self.sender.clone();
+        let sender = match req.priority {
+            Priority::High => self.high_priority_sender.clone(),
+            Priority::Low => self.low_priority_sender.clone(),
+        };
         let worker_id = self.worker_id;
 
         tokio::spawn(async move {
@@ -45,8 +134,8 @@ impl<TApiEndpoint: ApiEndpont> BatchWorkerHandle<TApiEndpoint> {
 
                     match err.0 {
                         BatchWorkerMessage::NewRequest(req) => {
-                            req.handle.reply_with_error(anyhow!(
-                                "Could not process request, please try again."
+                            req.handle.reply_with_error(ProxyError::Internal(
+                                "Could not process request, please try again.".to_string(),
                             ));
                         }
                     }
@@ -58,12 +147,42 @@ impl<TApiEndpoint: ApiEndpont> BatchWorkerHandle<TApiEndpoint> {
 impl<TApiEndpoint: ApiEndpont, TBatchExecutor: DataProvider<TApiEndpoint>>
     BatchWorker<TApiEndpoint, TBatchExecutor>
 {
-    fn flush_batch(&mut self) {
-        if self.request_store.is_empty() {
+    /// Composes the next batch: starved low priority requests first, then high priority
+    /// requests, and low priority requests fill the remaining capacity.
+    fn take_batch(&mut self) -> Vec<RequestClient<TApiEndpoint>> {
+        let now = Instant::now();
+        let mut batch = BatchBuilder::new(
+            self.max_batch_size,
+            self.max_batch_tokens,
+            self.length_sorting.clone(),
+        );
+
+        if let Some(starved_before) = now.checked_sub(self.low_priority_starvation_time) {
+            self.low_priority
+                .request_store
+                .take_into(&mut batch, starved_before);
+        }
+        self.high_priority.request_store.take_into(&mut batch, now);
+        self.low_priority.request_store.take_into(&mut batch, now);
+
+        let (_, requests) = batch.build();
+
+        requests
+    }
+
+    fn flush_batch(&mut self, permits: InFlightPermits) {
+        if self.is_empty() {
+            return;
+        }
+
+        let requests = self.take_batch();
+        let requests = self.discard_abandoned(requests);
+
+        if requests.is_empty() {
             return;
         }
 
-        let (current_batch_size, requests) = self.request_store.drain();
+        let current_batch_size = requests.iter().map(|r| r.data.len()).sum();
 
         let client_ids: Vec<_> = requests.iter().map(|r| r.handle.client_id).collect();
 
@@ -74,26 +193,128 @@ impl<TApiEndpoint: ApiEndpont, TBatchExecutor: DataProvider<TApiEndpoint>>
 
         let executor = Arc::clone(&self.data_provider);
         let grouping_params = Arc::clone(&self.grouping_params);
+        let adaptive_batching = self.adaptive_batching.clone();
+        let sort_by_length = self.length_sorting.is_some();
 
         tokio::spawn(async move {
-            batch_executor::execute_batch(executor, grouping_params, requests, current_batch_size)
-                .await
+            let started_at = Instant::now();
+            batch_executor::execute_batch(
+                executor,
+                grouping_params,
+                requests,
+                current_batch_size,
+                sort_by_length,
+            )
+            .await;
+            drop(permits);
+
+            if let Some(adaptive_batching) = adaptive_batching {
+                adaptive_batching.record_latency(started_at.elapsed());
+            }
         });
     }
 
-    fn handle_new_request(&mut self, req: RequestClient<TApiEndpoint>) {
-        info!(
-            "Accepted request from client. [worker_id={:#?}, client_id = {}]",
-            self.worker_id, req.handle.client_id
+    /// Drops requests whose clients went away, and fails requests whose deadline has passed,
+    /// so that they are not sent upstream.
+    fn discard_abandoned(
+        &self,
+        requests: Vec<RequestClient<TApiEndpoint>>,
+    ) -> Vec<RequestClient<TApiEndpoint>> {
+        let now = Instant::now();
+
+        requests
+            .into_iter()
+            .filter_map(|req| {
+                let reason = if req.handle.is_closed() {
+                    "cancelled"
+                } else if req.handle.is_expired(now) {
+                    "expired"
+                } else {
+                    return Some(req);
+                };
+
+                debug!(
+                    "Discarding abandoned request. [worker_id={:#?}, client_id = {}, reason = {reason}]",
+                    self.worker_id, req.handle.client_id
+                );
+                counter!(
+                    "batch_proxy_abandoned_requests_total",
+                    "endpoint" => TApiEndpoint::NAME,
+                    "reason" => reason
+                )
+                .increment(1);
+
+                if reason == "expired" {
+                    req.handle.reply_with_error(ProxyError::DeadlineExceeded);
+                }
+
+                None
+            })
+            .collect()
+    }
+
+    /// Rejects every queued request, used when the data provider becomes unavailable.
+    fn reject_pending(&mut self) {
+        let (_, high_priority_requests) = self.high_priority.request_store.drain();
+        let (_, low_priority_requests) = self.low_priority.request_store.drain();
+
+        warn!(
+            "Data provider is unavailable, rejecting queued requests. [worker_id={:#?}, requests = {}]",
+            self.worker_id,
+            high_priority_requests.len() + low_priority_requests.len()
         );
 
-        if let Some(req) = self.request_store.try_store(req) {
+        for req in high_priority_requests
+            .into_iter()
+            .chain(low_priority_requests)
+        {
+            req.handle.reply_with_error(ProxyError::UpstreamUnavailable);
+        }
+    }
+
+    fn handle_new_request(&mut self, req: RequestClient<TApiEndpoint>) {
+        if !*self.availability.borrow() {
             info!(
-                "Could not store request, max batch size was reached. Flushing current batch. [worker_id={:#?}]",
-                self.worker_id
+                "Data provider is unavailable, rejecting request. [worker_id={:#?}, client_id = {}]",
+                self.worker_id, req.handle.client_id
             );
-            self.flush_batch();
-            self.request_store.force_store(req);
+            req.handle.reply_with_error(ProxyError::UpstreamUnavailable);
+            return;
+        }
+
+        info!(
+            "Accepted request from client. [worker_id={:#?}, client_id = {}, priority = {:?}]",
+            self.worker_id, req.handle.client_id, req.priority
+        );
+
+        if let Some(adaptive_batching) = &self.adaptive_batching {
+            adaptive_batching.record_arrival(req.data.len());
+        }
+
+        let exceeds_token_budget = self
+            .max_batch_tokens
+            .is_some_and(|max_batch_tokens| req.token_count() > max_batch_tokens);
+        if req.data.len() <= self.batch_size_limit && !exceeds_token_budget {
+            self.store(req);
+            return;
+        }
+
+        debug!(
+            "Splitting request larger than the batch into chunks. [worker_id={:#?}, client_id = {}, request_size = {}]",
+            self.worker_id,
+            req.handle.client_id,
+            req.data.len()
+        );
+
+        for chunk in req.into_chunks(self.batch_size_limit, self.max_batch_tokens) {
+            self.store(chunk);
+        }
+    }
+
+    fn store(&mut self, req: RequestClient<TApiEndpoint>) {
+        match req.priority {
+            Priority::High => self.high_priority.request_store.store(req),
+            Priority::Low => self.low_priority.request_store.store(req),
         }
     }
 
@@ -102,6 +323,56 @@ impl<TApiEndpoint: ApiEndpont, TBatchExecutor: DataProvider<TApiEndpoint>>
             BatchWorkerMessage::NewRequest(req) => self.handle_new_request(req),
         }
     }
+
+    /// Applies the current adaptive batch size and window, if adaptive batching is enabled.
+    fn adapt(&mut self) {
+        if let Some(adaptive_batching) = &self.adaptive_batching {
+            self.max_batch_size = adaptive_batching.batch_size();
+            self.high_priority.max_waiting_time = adaptive_batching.waiting_time();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.high_priority.request_store.is_empty() && self.low_priority.request_store.is_empty()
+    }
+
+    /// Whether requests of the given size and token count fit into a single batch.
+    fn fits_batch(&self, batch_size: usize, token_count: usize) -> bool {
+        batch_size <= self.max_batch_size
+            && self
+                .max_batch_tokens
+                .is_none_or(|max_batch_tokens| token_count <= max_batch_tokens)
+    }
+
+    /// Whether the queue can take more requests. Once a queue holds more than a full batch,
+    /// new requests wait in its channel until it is flushed.
+    fn accepts(&self, queue: &PriorityQueue<TApiEndpoint>) -> bool {
+        !queue.is_closed
+            && self.fits_batch(
+                queue.request_store.current_batch_size(),
+                queue.request_store.current_token_count(),
+            )
+    }
+
+    /// The batch is flushed immediately once queued requests do not fit into it, otherwise
+    /// when the oldest request of either priority has waited long enough.
+    fn flush_deadline(&self) -> Option<Instant> {
+        let pending_batch_size = self.high_priority.request_store.current_batch_size()
+            + self.low_priority.request_store.current_batch_size();
+        let pending_token_count = self.high_priority.request_store.current_token_count()
+            + self.low_priority.request_store.current_token_count();
+        if !self.fits_batch(pending_batch_size, pending_token_count) {
+            return Some(Instant::now());
+        }
+
+        [
+            self.high_priority.flush_deadline(),
+            self.low_priority.flush_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
 }
 
 pub fn start<TApiEndpoint, TDataProvider>(
@@ -109,46 +380,447 @@ pub fn start<TApiEndpoint, TDataProvider>(
     batch_config: &BatchSettings,
     worker_id: Uuid,
     data_provider: Arc<TDataProvider>,
+    global_in_flight_batches: Arc<Semaphore>,
 ) -> BatchWorkerHandle<TApiEndpoint>
 where
     TApiEndpoint: ApiEndpont,
     TDataProvider: DataProvider<TApiEndpoint>,
 {
-    let (sender, receiver) = mpsc::channel(2048);
-    let flush_wait_duration = Duration::from_millis(batch_config.max_waiting_time_ms);
+    let (high_priority_sender, high_priority_receiver) = mpsc::channel(2048);
+    let (low_priority_sender, low_priority_receiver) = mpsc::channel(2048);
 
     let worker = BatchWorker {
-        request_store: RequestStore::new(batch_config.max_batch_size),
+        high_priority: PriorityQueue::new(high_priority_receiver, batch_config.max_waiting_time_ms),
+        low_priority: PriorityQueue::new(
+            low_priority_receiver,
+            batch_config.low_priority_max_waiting_time_ms,
+        ),
+        max_batch_size: batch_config.max_batch_size,
+        batch_size_limit: batch_config.max_batch_size,
+        max_batch_tokens: batch_config
+            .token_budget
+            .as_ref()
+            .map(|token_budget| token_budget.max_batch_tokens),
+        length_sorting: batch_config.length_sorting.clone(),
+        adaptive_batching: batch_config.adaptive.as_ref().map(|settings| {
+            Arc::new(AdaptiveBatching::new(
+                TApiEndpoint::NAME,
+                settings,
+                batch_config.max_batch_size,
+                batch_config.max_waiting_time_ms,
+            ))
+        }),
+        low_priority_starvation_time: Duration::from_millis(
+            batch_config.low_priority_starvation_ms,
+        ),
+        availability: data_provider.availability(),
+        in_flight_limits: InFlightLimits {
+            worker: Arc::new(Semaphore::new(
+                batch_config.max_in_flight_batches_per_worker,
+            )),
+            global: global_in_flight_batches,
+        },
         data_provider,
-        receiver,
         worker_id,
         grouping_params,
     };
 
-    tokio::spawn(async move { run_worker(worker, flush_wait_duration).await });
+    tokio::spawn(async move { run_worker(worker).await });
 
-    BatchWorkerHandle { sender, worker_id }
+    BatchWorkerHandle {
+        high_priority_sender,
+        low_priority_sender,
+        worker_id,
+    }
 }
 
 async fn run_worker<TApiEndpoint: ApiEndpont, TBatchExecutor: DataProvider<TApiEndpoint>>(
     mut worker: BatchWorker<TApiEndpoint, TBatchExecutor>,
-    flush_wait_duration: Duration,
 ) {
     loop {
+        worker.adapt();
+        let flush_deadline = worker.flush_deadline();
+        let in_flight_limits = worker.in_flight_limits.clone();
+        let accepts_high_priority = worker.accepts(&worker.high_priority);
+        let accepts_low_priority = worker.accepts(&worker.low_priority);
+
+        // While waiting for in-flight batches to complete, the worker keeps accepting requests
+        // until the batch is full, so batches grow when the upstream is saturated.
         tokio::select! {
-            msg = worker.receiver.recv() => {
+            msg = worker.high_priority.receiver.recv(), if accepts_high_priority => {
                 match msg {
                     Some(msg) => worker.handle_message(msg),
-                    None => {
-                        info!("Last sender was dropped, flushing batch and stopping batch worker.");
-                        worker.flush_batch();
-                        break;
-                    }
+                    None => worker.high_priority.is_closed = true,
                 }
             },
-            _ = tokio::time::sleep(flush_wait_duration) => {
-                worker.flush_batch();
+            msg = worker.low_priority.receiver.recv(), if accepts_low_priority => {
+                match msg {
+                    Some(msg) => worker.handle_message(msg),
+                    None => worker.low_priority.is_closed = true,
+                }
+            },
+            permits = async {
+                wait_for_deadline(flush_deadline).await;
+                in_flight_limits.acquire().await
+            } => {
+                worker.flush_batch(permits);
+            },
+            _ = wait_for_unavailability(&mut worker.availability), if flush_deadline.is_some() => {
+                worker.reject_pending();
             },
         }
+
+        if worker.high_priority.is_closed && worker.low_priority.is_closed {
+            info!("Last sender was dropped, flushing batch and stopping batch worker.");
+            while !worker.is_empty() {
+                let permits = worker.in_flight_limits.acquire().await;
+                worker.flush_batch(permits);
+            }
+            break;
+        }
+    }
+}
+
+/// Waits until the data provider becomes unavailable, or forever if it can not change.
+async fn wait_for_unavailability(availability: &mut watch::Receiver<bool>) {
+    if availability.wait_for(|available| !available).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Waits until the deadline has passed, or forever if there is nothing to wait for.
+async fn wait_for_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        batch::test_utils::{
+            RecordingDataProvider, TestApiEndpoint, TestGroupingParams, batch_settings,
+        },
+        error::{ProxyError, ProxyResult},
+        request::RequestOptions,
+        settings::{AdaptiveBatchingSettings, TokenBudgetSettings, TokenEstimate},
+    };
+
+    use super::*;
+
+    fn start_worker(
+        max_batch_size: usize,
+        max_waiting_time_ms: u64,
+    ) -> (
+        BatchWorkerHandle<TestApiEndpoint>,
+        mpsc::UnboundedReceiver<(Instant, usize)>,
+    ) {
+        let (data_provider, flushes) = RecordingDataProvider::new();
+        let handle = start_worker_with(
+            &batch_settings(max_batch_size, max_waiting_time_ms),
+            data_provider,
+            Arc::new(Semaphore::new(64)),
+        );
+
+        (handle, flushes)
+    }
+
+    fn start_worker_with(
+        batch_config: &BatchSettings,
+        data_provider: RecordingDataProvider,
+        global_in_flight_batches: Arc<Semaphore>,
+    ) -> BatchWorkerHandle<TestApiEndpoint> {
+        start(
+            Arc::new(TestGroupingParams(0)),
+            batch_config,
+            Uuid::new_v4(),
+            Arc::new(data_provider),
+            global_in_flight_batches,
+        )
+    }
+
+    fn put_request(
+        worker: &BatchWorkerHandle<TestApiEndpoint>,
+        data: Vec<u32>,
+    ) -> tokio::sync::oneshot::Receiver<ProxyResult<Vec<u32>>> {
+        put_request_with_priority(worker, data, Priority::High)
+    }
+
+    fn put_request_with_priority(
+        worker: &BatchWorkerHandle<TestApiEndpoint>,
+        data: Vec<u32>,
+        priority: Priority,
+    ) -> tokio::sync::oneshot::Receiver<ProxyResult<Vec<u32>>> {
+        let options = RequestOptions {
+            priority,
+            ..Default::default()
+        };
+        let (receiver, client) = RequestClient::new(data, Uuid::new_v4(), options);
+        worker.put_request(client);
+
+        receiver
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_steady_traffic_when_batch_not_full_should_flush_at_oldest_request_deadline() {
+        let (worker, mut flushes) = start_worker(32, 10);
+        let started_at = Instant::now();
+        let mut receivers = Vec::new();
+
+        for input in 0..10 {
+            let (receiver, client) =
+                RequestClient::new(vec![input], Uuid::new_v4(), RequestOptions::default());
+            receivers.push(receiver);
+            worker.put_request(client);
+            tokio::time::sleep(Duration::from_millis(3)).await;
+        }
+
+        let (flushed_at, batch_size) = flushes.recv().await.unwrap();
+
+        assert_eq!(flushed_at - started_at, Duration::from_millis(10));
+        assert_eq!(batch_size, 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_idle_worker_when_request_arrives_should_wait_full_window_from_arrival() {
+        let (worker, mut flushes) = start_worker(32, 10);
+
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        let arrived_at = Instant::now();
+        let (receiver, client) =
+            RequestClient::new(vec![1], Uuid::new_v4(), RequestOptions::default());
+        worker.put_request(client);
+
+        let (flushed_at, _) = flushes.recv().await.unwrap();
+
+        assert_eq!(flushed_at - arrived_at, Duration::from_millis(10));
+        assert_eq!(receiver.await.unwrap().unwrap(), vec![1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_worker_in_flight_limit_reached_when_deadline_passes_should_keep_filling_batch() {
+        let (data_provider, mut flushes) =
+            RecordingDataProvider::with_latency(Duration::from_millis(50));
+        let batch_config = BatchSettings {
+            max_in_flight_batches_per_worker: 1,
+            ..batch_settings(32, 10)
+        };
+        let worker = start_worker_with(&batch_config, data_provider, Arc::new(Semaphore::new(64)));
+        let started_at = Instant::now();
+
+        let mut receivers = vec![put_request(&worker, vec![0])];
+        tokio::time::sleep(Duration::from_millis(15)).await;
+        for input in 1..10 {
+            receivers.push(put_request(&worker, vec![input]));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let (first_flush_at, first_batch_size) = flushes.recv().await.unwrap();
+        let (second_flush_at, second_batch_size) = flushes.recv().await.unwrap();
+
+        assert_eq!(first_flush_at - started_at, Duration::from_millis(10));
+        assert_eq!(first_batch_size, 1);
+        assert_eq!(second_flush_at - started_at, Duration::from_millis(60));
+        assert_eq!(second_batch_size, 9);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_global_in_flight_limit_reached_when_batch_full_should_wait_for_permit() {
+        let global_in_flight_batches = Arc::new(Semaphore::new(1));
+        let (first_provider, mut first_flushes) =
+            RecordingDataProvider::with_latency(Duration::from_millis(50));
+        let (second_provider, mut second_flushes) = RecordingDataProvider::new();
+        let first_worker = start_worker_with(
+            &batch_settings(1, 10),
+            first_provider,
+            Arc::clone(&global_in_flight_batches),
+        );
+        let second_worker = start_worker_with(
+            &batch_settings(1, 10),
+            second_provider,
+            Arc::clone(&global_in_flight_batches),
+        );
+        let started_at = Instant::now();
+
+        let _first = put_request(&first_worker, vec![1]);
+        tokio::time::sleep(Duration::from_millis(15)).await;
+        let second = put_request(&second_worker, vec![2]);
+
+        let (first_flush_at, _) = first_flushes.recv().await.unwrap();
+        let (second_flush_at, _) = second_flushes.recv().await.unwrap();
+
+        assert_eq!(first_flush_at - started_at, Duration::from_millis(10));
+        assert_eq!(second_flush_at - started_at, Duration::from_millis(60));
+        assert_eq!(second.await.unwrap().unwrap(), vec![2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_queued_requests_when_provider_becomes_unavailable_should_reject_them() {
+        let (availability, availability_receiver) = watch::channel(true);
+        let (mut data_provider, mut flushes) = RecordingDataProvider::new();
+        data_provider.availability = availability_receiver;
+        let worker = start_worker_with(
+            &batch_settings(32, 10),
+            data_provider,
+            Arc::new(Semaphore::new(64)),
+        );
+
+        let queued = put_request(&worker, vec![1]);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        availability.send_replace(false);
+
+        assert!(matches!(
+            queued.await.unwrap(),
+            Err(ProxyError::UpstreamUnavailable)
+        ));
+        assert!(matches!(
+            put_request(&worker, vec![2]).await.unwrap(),
+            Err(ProxyError::UpstreamUnavailable)
+        ));
+        assert!(flushes.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_abandoned_requests_when_flushing_should_not_send_them_upstream() {
+        let (worker, mut flushes) = start_worker(32, 10);
+
+        let cancelled = put_request(&worker, vec![1]);
+        drop(cancelled);
+        let (expired, client) = RequestClient::new(
+            vec![2],
+            Uuid::new_v4(),
+            RequestOptions {
+                deadline: Some(Instant::now() + Duration::from_millis(5)),
+                ..Default::default()
+            },
+        );
+        worker.put_request(client);
+        let active = put_request(&worker, vec![3]);
+
+        let (_, batch_size) = flushes.recv().await.unwrap();
+
+        assert_eq!(batch_size, 1);
+        assert!(matches!(
+            expired.await.unwrap(),
+            Err(ProxyError::DeadlineExceeded)
+        ));
+        assert_eq!(active.await.unwrap().unwrap(), vec![3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_queued_low_priority_requests_when_high_priority_arrives_should_batch_it_first() {
+        let (worker, mut flushes) = start_worker(2, 10);
+        let started_at = Instant::now();
+
+        let first_low = put_request_with_priority(&worker, vec![1], Priority::Low);
+        let second_low = put_request_with_priority(&worker, vec![2], Priority::Low);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let high = put_request_with_priority(&worker, vec![3], Priority::High);
+
+        let (first_flush_at, first_batch_size) = flushes.recv().await.unwrap();
+        let (second_flush_at, _) = flushes.recv().await.unwrap();
+
+        assert_eq!(first_flush_at - started_at, Duration::from_millis(5));
+        assert_eq!(first_batch_size, 2);
+        assert_eq!(high.await.unwrap().unwrap(), vec![3]);
+        assert_eq!(first_low.await.unwrap().unwrap(), vec![1]);
+        assert_eq!(second_flush_at - started_at, Duration::from_millis(50));
+        assert_eq!(second_low.await.unwrap().unwrap(), vec![2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_steady_high_priority_traffic_when_low_priority_starves_should_batch_it_first() {
+        let (data_provider, _flushes) =
+            RecordingDataProvider::with_latency(Duration::from_millis(20));
+        let batch_config = BatchSettings {
+            max_in_flight_batches_per_worker: 1,
+            low_priority_starvation_ms: 30,
+            ..batch_settings(1, 10)
+        };
+        let worker = start_worker_with(&batch_config, data_provider, Arc::new(Semaphore::new(64)));
+        let started_at = Instant::now();
+
+        let low = put_request_with_priority(&worker, vec![0], Priority::Low);
+        tokio::spawn(async move {
+            let mut high = Vec::new();
+            for input in 1..20 {
+                high.push(put_request_with_priority(
+                    &worker,
+                    vec![input],
+                    Priority::High,
+                ));
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+
+        low.await.unwrap().unwrap();
+
+        // Batches are flushed every 20ms, the low priority request is starved at 30ms.
+        assert_eq!(started_at.elapsed(), Duration::from_millis(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_request_larger_than_batch_when_flushed_should_split_it_and_join_results() {
+        let (worker, mut flushes) = start_worker(2, 10);
+
+        let oversized = put_request(&worker, vec![1, 2, 3, 4, 5]);
+        let other = put_request(&worker, vec![6]);
+
+        assert_eq!(oversized.await.unwrap().unwrap(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(other.await.unwrap().unwrap(), vec![6]);
+        for _ in 0..3 {
+            let (_, batch_size) = flushes.recv().await.unwrap();
+            assert_eq!(batch_size, 2);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_adaptive_batch_size_shrunk_when_request_arrives_should_split_by_configured_size()
+    {
+        let (data_provider, mut flushes) = RecordingDataProvider::new();
+        let mut batch_config = batch_settings(32, 10);
+        batch_config.adaptive = Some(AdaptiveBatchingSettings {
+            min_batch_size: 1,
+            min_waiting_time_ms: 1,
+            smoothing_factor: 1.0,
+        });
+        let worker = start_worker_with(&batch_config, data_provider, Arc::new(Semaphore::new(64)));
+
+        for input in [1, 2] {
+            put_request(&worker, vec![input]).await.unwrap().unwrap();
+            flushes.recv().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let request = put_request(&worker, vec![3, 4, 5, 6, 7]);
+
+        assert_eq!(request.await.unwrap().unwrap(), vec![3, 4, 5, 6, 7]);
+        let (_, batch_size) = flushes.recv().await.unwrap();
+        assert_eq!(batch_size, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_request_over_token_budget_when_flushed_should_split_it_by_tokens() {
+        let (data_provider, mut flushes) = RecordingDataProvider::new();
+        let mut batch_config = batch_settings(32, 10);
+        batch_config.token_budget = Some(TokenBudgetSettings {
+            max_batch_tokens: 10,
+            estimate: TokenEstimate::Whitespace,
+        });
+        let worker = start_worker_with(&batch_config, data_provider, Arc::new(Semaphore::new(64)));
+
+        let (receiver, mut client) =
+            RequestClient::new(vec![1, 2, 3], Uuid::new_v4(), RequestOptions::default());
+        client.token_counts = vec![4, 4, 4];
+        worker.put_request(client);
+
+        assert_eq!(receiver.await.unwrap().unwrap(), vec![1, 2, 3]);
+        let mut batch_sizes = vec![
+            flushes.recv().await.unwrap().1,
+            flushes.recv().await.unwrap().1,
+        ];
+        batch_sizes.sort();
+        assert_eq!(batch_sizes, vec![1, 2]);
     }
 }
@@ -1,6 +1,7 @@
 use async_trait::async_trait;
+use tokio::sync::watch;
 
-use crate::api::endpoint::ApiEndpont;
+use crate::{api::endpoint::ApiEndpont, error::ProxyResult};
 
 use super::batch_executor::Batch;
 
@@ -9,5 +10,10 @@ pub trait DataProvider<TApiEndpoint: ApiEndpont>: Send + Sync + 'static {
     async fn get_data_for_batch(
         &self,
         batch: &Batch<TApiEndpoint>,
-    ) -> anyhow::Result<Vec<TApiEndpoint::ApiResponseItem>>;
+    ) -> ProxyResult<Vec<TApiEndpoint::ApiResponseItem>>;
+
+    /// Receives `false` while batches can not be served at all, queued requests are rejected then.
+    fn availability(&self) -> watch::Receiver<bool> {
+        watch::channel(true).1
+    }
 }
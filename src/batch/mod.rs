@@ -1,9 +1,13 @@
 pub mod batch_manager;
 pub mod data_provider;
 
+mod adaptive_batching;
 mod batch_executor;
 mod batch_worker;
 mod request_store;
+#[cfg(test)]
+pub mod test_utils;
+pub mod token_counter;
 
 pub use batch_executor::Batch;
 pub use data_provider::DataProvider;
@@ -1,49 +1,164 @@
-use crate::{api::endpoint::ApiEndpont, request::RequestClient};
+use std::collections::VecDeque;
 
+use tokio::time::Instant;
+
+use crate::{api::endpoint::ApiEndpont, request::RequestClient, settings::LengthSortingSettings};
+
+use super::token_counter::estimate_input_length;
+
+/// Queue of requests waiting to be batched, in arrival order.
 pub struct RequestStore<TApiEndpoint: ApiEndpont> {
-    pending_requests: Vec<RequestClient<TApiEndpoint>>,
+    pending_requests: VecDeque<StoredRequest<TApiEndpoint>>,
+    current_batch_size: usize,
+    current_token_count: usize,
+}
+
+struct StoredRequest<TApiEndpoint: ApiEndpont> {
+    request: RequestClient<TApiEndpoint>,
+    stored_at: Instant,
+    /// Start of the current flush window, later than `stored_at` for held back requests.
+    waiting_since: Instant,
+    /// Estimated token length of the longest input.
+    length: usize,
+    /// Number of batches flushed without this request because of its length.
+    held_flushes: u32,
+}
+
+/// Requests taken from one or more stores for the next batch.
+pub struct BatchBuilder<TApiEndpoint: ApiEndpont> {
+    requests: Vec<RequestClient<TApiEndpoint>>,
     current_batch_size: usize,
+    current_token_count: usize,
     max_batch_size: usize,
+    max_batch_tokens: Option<usize>,
+    /// Takes only requests of similar length to the first taken request, if set.
+    length_sorting: Option<LengthSortingSettings>,
+    anchor_length: Option<usize>,
+    created_at: Instant,
 }
 
-impl<TApiEndpoint: ApiEndpont> RequestStore<TApiEndpoint> {
-    pub fn new(max_batch_size: usize) -> Self {
+impl<TApiEndpoint: ApiEndpont> BatchBuilder<TApiEndpoint> {
+    pub fn new(
+        max_batch_size: usize,
+        max_batch_tokens: Option<usize>,
+        length_sorting: Option<LengthSortingSettings>,
+    ) -> Self {
         Self {
-            pending_requests: Vec::new(),
+            requests: Vec::new(),
             current_batch_size: 0,
+            current_token_count: 0,
             max_batch_size,
+            max_batch_tokens,
+            length_sorting,
+            anchor_length: None,
+            created_at: Instant::now(),
         }
     }
 
-    /// Tries to store request, returns it back if maximum batch size has been reached.
-    pub fn try_store(
-        &mut self,
-        req: RequestClient<TApiEndpoint>,
-    ) -> Option<RequestClient<TApiEndpoint>> {
-        let data_count = req.data.len();
+    /// Requests that were held back for too many flushes are taken regardless of their length.
+    fn matches_length(&self, stored: &StoredRequest<TApiEndpoint>) -> bool {
+        let (Some(length_sorting), Some(anchor_length)) =
+            (&self.length_sorting, self.anchor_length)
+        else {
+            return true;
+        };
 
-        if self.current_batch_size + data_count > self.max_batch_size {
-            return Some(req);
-        }
+        let (shorter, longer) = match stored.length < anchor_length {
+            true => (stored.length, anchor_length),
+            false => (anchor_length, stored.length),
+        };
 
-        self.current_batch_size += data_count;
-        self.pending_requests.push(req);
+        stored.held_flushes >= length_sorting.max_held_flushes
+            || longer as f64 <= shorter.max(1) as f64 * length_sorting.max_length_ratio
+    }
 
-        None
+    /// A request larger than the maximum batch size or token budget still fits into an empty
+    /// batch, so that it does not block the queue.
+    fn fits(&self, req: &RequestClient<TApiEndpoint>) -> bool {
+        self.requests.is_empty()
+            || (self.current_batch_size + req.data.len() <= self.max_batch_size
+                && self.max_batch_tokens.is_none_or(|max_batch_tokens| {
+                    self.current_token_count + req.token_count() <= max_batch_tokens
+                }))
     }
 
-    /// Stores request ignoring the maximum batch size setting.
-    pub fn force_store(&mut self, req: RequestClient<TApiEndpoint>) {
-        let data_count = req.data.len();
+    /// Returns taken requests and their batch size.
+    pub fn build(self) -> (usize, Vec<RequestClient<TApiEndpoint>>) {
+        (self.current_batch_size, self.requests)
+    }
+}
 
-        self.current_batch_size += data_count;
-        self.pending_requests.push(req);
+impl<TApiEndpoint: ApiEndpont> RequestStore<TApiEndpoint> {
+    pub fn new() -> Self {
+        Self {
+            pending_requests: VecDeque::new(),
+            current_batch_size: 0,
+            current_token_count: 0,
+        }
+    }
+
+    pub fn store(&mut self, req: RequestClient<TApiEndpoint>) {
+        let now = Instant::now();
+        self.current_batch_size += req.data.len();
+        self.current_token_count += req.token_count();
+        self.pending_requests.push_back(StoredRequest {
+            length: req
+                .data
+                .iter()
+                .map(estimate_input_length)
+                .max()
+                .unwrap_or(0),
+            request: req,
+            stored_at: now,
+            waiting_since: now,
+            held_flushes: 0,
+        });
+    }
+
+    /// Moves requests stored no later than `stored_before` into the batch, in arrival order,
+    /// while they fit into it. With length sorting, requests of dissimilar length are skipped.
+    pub fn take_into(&mut self, batch: &mut BatchBuilder<TApiEndpoint>, stored_before: Instant) {
+        let mut skipped = VecDeque::new();
+
+        while let Some(front) = self.pending_requests.front() {
+            if front.stored_at > stored_before || !batch.fits(&front.request) {
+                break;
+            }
+
+            let mut stored = self.pending_requests.pop_front().unwrap();
+            if !batch.matches_length(&stored) {
+                // Held back requests wait for another flush window.
+                if stored.waiting_since < batch.created_at {
+                    stored.held_flushes += 1;
+                    stored.waiting_since = batch.created_at;
+                }
+                skipped.push_back(stored);
+                continue;
+            }
+
+            let StoredRequest {
+                request, length, ..
+            } = stored;
+            self.current_batch_size -= request.data.len();
+            self.current_token_count -= request.token_count();
+            batch.current_batch_size += request.data.len();
+            batch.current_token_count += request.token_count();
+            batch.anchor_length.get_or_insert(length);
+            batch.requests.push(request);
+        }
+
+        skipped.append(&mut self.pending_requests);
+        self.pending_requests = skipped;
     }
 
     /// Empties the store, returning stored requests and current batch size.
     pub fn drain(&mut self) -> (usize, Vec<RequestClient<TApiEndpoint>>) {
-        let requests = std::mem::take(&mut self.pending_requests);
+        let requests = std::mem::take(&mut self.pending_requests)
+            .into_iter()
+            .map(|stored| stored.request)
+            .collect();
         let current_batch_size = std::mem::take(&mut self.current_batch_size);
+        self.current_token_count = 0;
 
         (current_batch_size, requests)
     }
@@ -51,20 +166,38 @@ impl<TApiEndpoint: ApiEndpont> RequestStore<TApiEndpoint> {
     pub fn is_empty(&self) -> bool {
         self.pending_requests.is_empty()
     }
+
+    /// Number of inputs of all stored requests.
+    pub fn current_batch_size(&self) -> usize {
+        self.current_batch_size
+    }
+
+    /// Token count of all stored requests.
+    pub fn current_token_count(&self) -> usize {
+        self.current_token_count
+    }
+
+    /// Start of the flush window of the request that has waited longest to be flushed.
+    pub fn oldest_request_at(&self) -> Option<Instant> {
+        self.pending_requests
+            .iter()
+            .map(|stored| stored.waiting_since)
+            .min()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use uuid::Uuid;
 
-    use crate::api::endpoint::GroupingParams;
+    use crate::{api::endpoint::GroupingParams, request::RequestOptions};
 
     use super::*;
     struct TestApiEndpoint;
-    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    #[derive(Debug, Clone, serde::Serialize, PartialEq, Eq, Hash)]
     struct TestGroupingParams;
     impl GroupingParams for TestGroupingParams {
-        type DataItem = ();
+        type DataItem = String;
 
         type ApiRequest = ();
 
@@ -78,22 +211,32 @@ mod tests {
     }
 
     impl ApiEndpont for TestApiEndpoint {
+        const NAME: &'static str = "test";
+
         type ApiRequest = ();
         type ApiResponseItem = ();
-        type DataItem = ();
+        type DataItem = String;
         type GroupingParams = TestGroupingParams;
     }
 
     fn client(data_count: usize) -> RequestClient<TestApiEndpoint> {
-        let (_, client) = RequestClient::new(vec![(); data_count], Uuid::new_v4());
+        text_client("", data_count)
+    }
+
+    fn text_client(text: &str, data_count: usize) -> RequestClient<TestApiEndpoint> {
+        let (_, client) = RequestClient::new(
+            vec![text.to_string(); data_count],
+            Uuid::new_v4(),
+            RequestOptions::default(),
+        );
         client
     }
 
     #[test]
     fn given_request_when_retrieved_should_give_correct_data_count() {
         let client = client(12);
-        let mut store = RequestStore::new(2);
-        store.force_store(client);
+        let mut store = RequestStore::new();
+        store.store(client);
 
         let (data_size, _) = store.drain();
 
@@ -101,16 +244,82 @@ mod tests {
     }
 
     #[test]
-    fn given_request_when_stored_should_return_request_if_larger_than_configured_max() {
-        let client = client(2);
-        let mut store = RequestStore::new(1);
-        let result = store.try_store(client);
+    fn given_requests_when_taken_should_stop_at_first_request_that_does_not_fit() {
+        let mut store = RequestStore::new();
+        for data_count in [2, 1, 2, 1] {
+            store.store(client(data_count));
+        }
+        let mut batch = BatchBuilder::new(4, None, None);
+
+        store.take_into(&mut batch, Instant::now());
+        let (data_size, data) = batch.build();
+
+        assert_eq!(data_size, 3);
+        assert_eq!(data.len(), 2);
+        assert_eq!(store.current_batch_size(), 3);
+    }
+
+    #[test]
+    fn given_request_larger_than_max_when_batch_empty_should_take_it_alone() {
+        let mut store = RequestStore::new();
+        store.store(client(3));
+        store.store(client(1));
+        let mut batch = BatchBuilder::new(2, None, None);
+
+        store.take_into(&mut batch, Instant::now());
+        let (data_size, data) = batch.build();
+
+        assert_eq!(data_size, 3);
+        assert_eq!(data.len(), 1);
+        assert_eq!(store.current_batch_size(), 1);
+    }
+
+    #[test]
+    fn given_token_budget_when_taken_should_stop_before_exceeding_it() {
+        let mut store = RequestStore::new();
+        for token_count in [300, 500, 100] {
+            let mut client = client(1);
+            client.token_counts = vec![token_count];
+            store.store(client);
+        }
+        let mut batch = BatchBuilder::new(32, Some(512), None);
+
+        store.take_into(&mut batch, Instant::now());
+        let (data_size, _) = batch.build();
+
+        assert_eq!(data_size, 1);
+        assert_eq!(store.current_token_count(), 600);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_length_sorting_when_taken_should_hold_dissimilar_requests_for_limited_flushes() {
+        let length_sorting = LengthSortingSettings {
+            max_length_ratio: 2.0,
+            max_held_flushes: 1,
+        };
+        let short = "a".repeat(40);
+        let long = "a".repeat(400);
+        let mut store = RequestStore::new();
+        for text in [&short, &long, &short] {
+            store.store(text_client(text, 1));
+        }
+
+        tokio::time::advance(std::time::Duration::from_millis(1)).await;
+        let mut first = BatchBuilder::new(32, None, Some(length_sorting.clone()));
+        store.take_into(&mut first, Instant::now());
+        let (first_size, _) = first.build();
 
-        assert!(result.is_some(), "Should return request back.");
+        assert_eq!(first_size, 2);
+        assert_eq!(store.current_batch_size(), 1);
 
-        let (data_size, data) = store.drain();
+        let mut high_priority_store = RequestStore::new();
+        high_priority_store.store(text_client(&short, 1));
+        let mut second = BatchBuilder::new(32, None, Some(length_sorting));
+        high_priority_store.take_into(&mut second, Instant::now());
+        store.take_into(&mut second, Instant::now());
+        let (second_size, _) = second.build();
 
-        assert_eq!(data_size, 0);
-        assert!(data.is_empty())
+        assert_eq!(second_size, 2);
+        assert!(store.is_empty());
     }
 }
@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, watch},
+    time::Instant,
+};
+
+use crate::{
+    api::endpoint::{
+        ApiEndpont, GroupingParams, MeasurableInput,
+        embed_endpoint::{EmbedApiEndpoint, EmbedApiRequestInputs},
+    },
+    error::ProxyResult,
+    settings::BatchSettings,
+};
+
+use super::{Batch, DataProvider};
+
+pub struct TestApiEndpoint;
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
+pub struct TestGroupingParams(pub u32);
+
+impl GroupingParams for TestGroupingParams {
+    type DataItem = u32;
+    type ApiRequest = (u32, Vec<u32>);
+
+    fn to_request(&self, data: Vec<Self::DataItem>) -> Self::ApiRequest {
+        (self.0, data)
+    }
+
+    fn decompose_api_request(api_request: Self::ApiRequest) -> (Vec<Self::DataItem>, Self) {
+        let (group, data) = api_request;
+        (data, TestGroupingParams(group))
+    }
+}
+
+/// Every test input counts as many tokens as its value.
+impl MeasurableInput for u32 {
+    fn texts(&self) -> Vec<&str> {
+        Vec::new()
+    }
+
+    fn token_count(&self) -> Option<usize> {
+        Some(*self as usize)
+    }
+}
+
+impl ApiEndpont for TestApiEndpoint {
+    const NAME: &'static str = "test";
+
+    type ApiRequest = (u32, Vec<u32>);
+    type ApiResponseItem = u32;
+    type DataItem = u32;
+    type GroupingParams = TestGroupingParams;
+}
+
+/// Echoes inputs back after the configured latency and reports when each batch was executed.
+pub struct RecordingDataProvider {
+    flushes: mpsc::UnboundedSender<(Instant, usize)>,
+    latency: Duration,
+    pub availability: watch::Receiver<bool>,
+}
+
+impl RecordingDataProvider {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<(Instant, usize)>) {
+        Self::with_latency(Duration::ZERO)
+    }
+
+    pub fn with_latency(latency: Duration) -> (Self, mpsc::UnboundedReceiver<(Instant, usize)>) {
+        let (flushes, flush_receiver) = mpsc::unbounded_channel();
+
+        (
+            Self {
+                flushes,
+                latency,
+                availability: watch::channel(true).1,
+            },
+            flush_receiver,
+        )
+    }
+}
+
+#[async_trait]
+impl DataProvider<TestApiEndpoint> for RecordingDataProvider {
+    async fn get_data_for_batch(&self, batch: &Batch<TestApiEndpoint>) -> ProxyResult<Vec<u32>> {
+        let (_, inputs) = batch.api_parameters().clone();
+        let _ = self.flushes.send((Instant::now(), inputs.len()));
+        tokio::time::sleep(self.latency).await;
+
+        Ok(inputs)
+    }
+
+    fn availability(&self) -> watch::Receiver<bool> {
+        self.availability.clone()
+    }
+}
+
+/// Returns a single-dimension embedding for every input.
+#[async_trait]
+impl DataProvider<EmbedApiEndpoint> for RecordingDataProvider {
+    async fn get_data_for_batch(
+        &self,
+        batch: &Batch<EmbedApiEndpoint>,
+    ) -> ProxyResult<Vec<Vec<f64>>> {
+        let input_count = match &batch.api_parameters().inputs {
+            EmbedApiRequestInputs::Vec(inputs) => inputs.len(),
+            EmbedApiRequestInputs::Single(_) => 1,
+        };
+        let _ = self.flushes.send((Instant::now(), input_count));
+        tokio::time::sleep(self.latency).await;
+
+        Ok(vec![vec![0.0]; input_count])
+    }
+
+    fn availability(&self) -> watch::Receiver<bool> {
+        self.availability.clone()
+    }
+}
+
+pub fn batch_settings(max_batch_size: usize, max_waiting_time_ms: u64) -> BatchSettings {
+    BatchSettings {
+        max_batch_size,
+        max_waiting_time_ms,
+        low_priority_max_waiting_time_ms: max_waiting_time_ms * 5,
+        low_priority_starvation_ms: 1000,
+        max_workers: 16,
+        worker_idle_timeout_ms: 60_000,
+        max_in_flight_batches_per_worker: 64,
+        max_in_flight_batches: 64,
+        adaptive: None,
+        token_budget: None,
+        length_sorting: None,
+        overrides: Vec::new(),
+    }
+}
@@ -0,0 +1,144 @@
+use async_trait::async_trait;
+use log::warn;
+
+use crate::{
+    api::endpoint::{ApiEndpont, GroupingParams, MeasurableInput},
+    error::ProxyResult,
+};
+
+/// Measures inputs for token-budget batching.
+#[async_trait]
+pub trait TokenCounter: Send + Sync + 'static {
+    /// Token counts of the texts, in the same order.
+    async fn count_tokens(&self, texts: &[&str]) -> ProxyResult<Vec<usize>>;
+}
+
+/// Estimates a token per four characters.
+pub struct CharacterTokenCounter;
+
+#[async_trait]
+impl TokenCounter for CharacterTokenCounter {
+    async fn count_tokens(&self, texts: &[&str]) -> ProxyResult<Vec<usize>> {
+        Ok(texts.iter().map(|text| character_estimate(text)).collect())
+    }
+}
+
+/// Estimates a token per whitespace separated word.
+pub struct WhitespaceTokenCounter;
+
+#[async_trait]
+impl TokenCounter for WhitespaceTokenCounter {
+    async fn count_tokens(&self, texts: &[&str]) -> ProxyResult<Vec<usize>> {
+        Ok(texts
+            .iter()
+            .map(|text| text.split_whitespace().count())
+            .collect())
+    }
+}
+
+fn character_estimate(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Length of the input in tokens, estimated from its texts if it is not tokenized.
+pub fn estimate_input_length(item: &impl MeasurableInput) -> usize {
+    item.token_count().unwrap_or_else(|| {
+        item.texts()
+            .iter()
+            .map(|text| character_estimate(text))
+            .sum()
+    })
+}
+
+/// Token counts of the request inputs, in the same order, including the texts shared by every
+/// input. Falls back to the character estimate if the counter fails, so that requests are not
+/// rejected because they could not be measured.
+pub async fn count_input_tokens<TApiEndpoint: ApiEndpont>(
+    token_counter: &dyn TokenCounter,
+    grouping_params: &TApiEndpoint::GroupingParams,
+    data: &[TApiEndpoint::DataItem],
+) -> Vec<usize> {
+    let shared_texts = grouping_params.shared_texts();
+    let texts: Vec<&str> = data
+        .iter()
+        .flat_map(|item| item.texts())
+        .chain(shared_texts.iter().copied())
+        .collect();
+    let text_counts = if texts.is_empty() {
+        Vec::new()
+    } else {
+        match token_counter.count_tokens(&texts).await {
+            Ok(counts) => counts,
+            Err(err) => {
+                warn!("Could not count tokens, estimating them from text length. [error = {err}]");
+                texts.iter().map(|text| character_estimate(text)).collect()
+            }
+        }
+    };
+
+    let mut text_counts = text_counts.into_iter();
+    let input_counts: Vec<usize> = data
+        .iter()
+        .map(|item| {
+            let text_tokens: usize = text_counts.by_ref().take(item.texts().len()).sum();
+            item.token_count().unwrap_or(0) + text_tokens
+        })
+        .collect();
+    let shared_tokens: usize = text_counts.sum();
+
+    input_counts
+        .into_iter()
+        .map(|input_tokens| input_tokens + shared_tokens)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::api::endpoint::{
+        embed_endpoint::{EmbedApiEndpoint, EmbedRequestGroupingParams},
+        rerank_endpoint::{RerankApiEndpoint, RerankRequestGroupingParams},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn given_text_and_token_ids_when_counted_should_count_each_input() {
+        let (data, grouping_params) = EmbedRequestGroupingParams::decompose_api_request(
+            serde_json::from_value(json!({
+                "inputs": ["what is the capital of France", [101, 2054, 102]]
+            }))
+            .unwrap(),
+        );
+
+        let tokens = count_input_tokens::<EmbedApiEndpoint>(
+            &WhitespaceTokenCounter,
+            &grouping_params,
+            &data,
+        )
+        .await;
+
+        assert_eq!(tokens, vec![6, 3]);
+    }
+
+    #[tokio::test]
+    async fn given_rerank_query_when_counted_should_add_it_to_every_text() {
+        let (data, grouping_params) = RerankRequestGroupingParams::decompose_api_request(
+            serde_json::from_value(json!({
+                "query": "what is the capital of France",
+                "texts": ["Paris", "Berlin is the capital of Germany"]
+            }))
+            .unwrap(),
+        );
+
+        let tokens = count_input_tokens::<RerankApiEndpoint>(
+            &WhitespaceTokenCounter,
+            &grouping_params,
+            &data,
+        )
+        .await;
+
+        assert_eq!(tokens, vec![7, 12]);
+    }
+}
@@ -0,0 +1,374 @@
+use std::{
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+use log::{info, warn};
+use metrics::{counter, gauge};
+use redb::{Database, Durability, ReadableTable, TableDefinition, WriteTransaction};
+use sha2::{Digest, Sha256};
+
+use crate::{api::endpoint::ApiEndpont, settings::DiskCacheSettings};
+
+/// Entry key hash to the insertion sequence number and the serialized result.
+const ENTRIES: TableDefinition<&[u8], (u64, &[u8])> = TableDefinition::new("entries");
+/// Insertion sequence number to the entry key hash, oldest entries are evicted first.
+const INSERTION_ORDER: TableDefinition<u64, &[u8]> = TableDefinition::new("insertion_order");
+const METADATA: TableDefinition<&str, u64> = TableDefinition::new("metadata");
+const VERSION: TableDefinition<&str, &str> = TableDefinition::new("version");
+
+const SIZE_BYTES: &str = "size_bytes";
+const NEXT_SEQUENCE: &str = "next_sequence";
+
+/// Entries waiting to be written, further results are not cached until the writer catches up.
+const MAX_PENDING_WRITES: usize = 10_000;
+
+#[derive(Default)]
+struct PendingWrites {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    is_writing: bool,
+}
+
+/// Results stored in an embedded database, shared by all endpoints. Entries cached for
+/// another version are purged when the store is opened.
+pub struct DiskCacheStore {
+    database: Database,
+    version: String,
+    max_size_bytes: u64,
+    /// Entries queued for the single background writer, which stores them in one transaction.
+    pending_writes: Mutex<PendingWrites>,
+}
+
+impl DiskCacheStore {
+    pub fn open(settings: &DiskCacheSettings) -> anyhow::Result<Self> {
+        let database = Database::create(&settings.path)?;
+
+        let transaction = database.begin_write()?;
+        let stored_version = transaction
+            .open_table(VERSION)?
+            .get("version")?
+            .map(|version| version.value().to_string());
+
+        if stored_version.as_deref() != Some(settings.version.as_str()) {
+            info!(
+                "Disk cache version changed, purging cached results. [previous_version = {stored_version:?}, version = {}]",
+                settings.version
+            );
+            Self::clear(&transaction)?;
+            transaction
+                .open_table(VERSION)?
+                .insert("version", settings.version.as_str())?;
+        }
+        transaction.commit()?;
+
+        Ok(Self {
+            database,
+            version: settings.version.clone(),
+            max_size_bytes: settings.max_size_bytes,
+            pending_writes: Mutex::default(),
+        })
+    }
+
+    fn clear(transaction: &WriteTransaction) -> anyhow::Result<()> {
+        transaction.delete_table(ENTRIES)?;
+        transaction.delete_table(INSERTION_ORDER)?;
+        transaction.delete_table(METADATA)?;
+
+        // Tables are created again, so that read transactions can open them.
+        transaction.open_table(ENTRIES)?;
+        transaction.open_table(INSERTION_ORDER)?;
+        transaction.open_table(METADATA)?;
+
+        Ok(())
+    }
+
+    /// Removes every cached result.
+    pub fn purge(&self) -> anyhow::Result<()> {
+        let transaction = self.database.begin_write()?;
+        Self::clear(&transaction)?;
+        transaction.commit()?;
+
+        gauge!("batch_proxy_cache_size_bytes", "cache" => "disk").set(0.0);
+        info!("Disk cache was purged.");
+
+        Ok(())
+    }
+
+    fn get(&self, keys: &[Vec<u8>]) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
+        let transaction = self.database.begin_read()?;
+        let entries = transaction.open_table(ENTRIES)?;
+
+        keys.iter()
+            .map(|key| {
+                let entry = entries.get(key.as_slice())?;
+                Ok(entry.map(|entry| entry.value().1.to_vec()))
+            })
+            .collect()
+    }
+
+    /// Stores the entries and evicts the oldest ones while the store is over its size limit.
+    fn insert(&self, new_entries: Vec<(Vec<u8>, Vec<u8>)>) -> anyhow::Result<()> {
+        let mut transaction = self.database.begin_write()?;
+        // Results can be computed again, so losing the latest writes on a crash is acceptable.
+        transaction.set_durability(Durability::Eventual);
+
+        {
+            let mut entries = transaction.open_table(ENTRIES)?;
+            let mut insertion_order = transaction.open_table(INSERTION_ORDER)?;
+            let mut metadata = transaction.open_table(METADATA)?;
+
+            let mut size_bytes = metadata.get(SIZE_BYTES)?.map_or(0, |size| size.value());
+            let mut next_sequence = metadata.get(NEXT_SEQUENCE)?.map_or(0, |seq| seq.value());
+
+            for (key, value) in new_entries {
+                let entry_size = (key.len() + value.len()) as u64;
+                if entry_size > self.max_size_bytes || entries.get(key.as_slice())?.is_some() {
+                    continue;
+                }
+
+                entries.insert(key.as_slice(), (next_sequence, value.as_slice()))?;
+                insertion_order.insert(next_sequence, key.as_slice())?;
+                size_bytes += entry_size;
+                next_sequence += 1;
+            }
+
+            while size_bytes > self.max_size_bytes {
+                let Some((_, key)) = insertion_order.pop_first()? else {
+                    break;
+                };
+                if let Some(evicted) = entries.remove(key.value())? {
+                    size_bytes -= (key.value().len() + evicted.value().1.len()) as u64;
+                }
+            }
+
+            metadata.insert(SIZE_BYTES, size_bytes)?;
+            metadata.insert(NEXT_SEQUENCE, next_sequence)?;
+            gauge!("batch_proxy_cache_size_bytes", "cache" => "disk").set(size_bytes as f64);
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Queues the entries for the background writer, starting it if it is not running.
+    fn queue_insert(self: &Arc<Self>, new_entries: Vec<(Vec<u8>, Vec<u8>)>) {
+        let mut pending_writes = self.pending_writes.lock().unwrap();
+
+        let free_slots = MAX_PENDING_WRITES.saturating_sub(pending_writes.entries.len());
+        if new_entries.len() > free_slots {
+            warn!(
+                "Disk cache writer is behind, results are not cached. [dropped_entries = {}]",
+                new_entries.len() - free_slots
+            );
+        }
+        pending_writes
+            .entries
+            .extend(new_entries.into_iter().take(free_slots));
+
+        if pending_writes.is_writing || pending_writes.entries.is_empty() {
+            return;
+        }
+        pending_writes.is_writing = true;
+
+        let store = Arc::clone(self);
+        tokio::task::spawn_blocking(move || store.write_pending());
+    }
+
+    /// Writes queued entries until none are left.
+    fn write_pending(&self) {
+        loop {
+            let entries = {
+                let mut pending_writes = self.pending_writes.lock().unwrap();
+                if pending_writes.entries.is_empty() {
+                    pending_writes.is_writing = false;
+                    return;
+                }
+                std::mem::take(&mut pending_writes.entries)
+            };
+
+            if let Err(err) = self.insert(entries) {
+                warn!("Could not write to the disk cache. [error = {err}]");
+            }
+        }
+    }
+}
+
+/// Results of single inputs of the endpoint, keyed by a hash of the cache version, the endpoint,
+/// the grouping parameters and the input.
+pub struct DiskCache<TApiEndpoint: ApiEndpont> {
+    store: Arc<DiskCacheStore>,
+    _endpoint: PhantomData<fn() -> TApiEndpoint>,
+}
+
+impl<TApiEndpoint: ApiEndpont> DiskCache<TApiEndpoint> {
+    pub fn new(store: Arc<DiskCacheStore>) -> Self {
+        Self {
+            store,
+            _endpoint: PhantomData,
+        }
+    }
+
+    fn key(
+        &self,
+        grouping_params: &TApiEndpoint::GroupingParams,
+        input: &TApiEndpoint::DataItem,
+    ) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        for part in [
+            self.store.version.as_bytes(),
+            TApiEndpoint::NAME.as_bytes(),
+            &serde_json::to_vec(grouping_params).unwrap_or_default(),
+            &serde_json::to_vec(input).unwrap_or_default(),
+        ] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+
+        hasher.finalize().to_vec()
+    }
+
+    /// Cached results of the inputs, `None` for inputs that are not cached or could not be read.
+    pub async fn get_many(
+        &self,
+        grouping_params: &TApiEndpoint::GroupingParams,
+        inputs: &[&TApiEndpoint::DataItem],
+    ) -> Vec<Option<TApiEndpoint::ApiResponseItem>> {
+        let keys: Vec<_> = inputs
+            .iter()
+            .map(|input| self.key(grouping_params, input))
+            .collect();
+        let store = Arc::clone(&self.store);
+
+        let values = match tokio::task::spawn_blocking(move || store.get(&keys)).await {
+            Ok(Ok(values)) => values,
+            Ok(Err(err)) => {
+                warn!("Could not read from the disk cache. [error = {err}]");
+                vec![None; inputs.len()]
+            }
+            Err(err) => {
+                warn!("Could not read from the disk cache. [error = {err}]");
+                vec![None; inputs.len()]
+            }
+        };
+
+        let results: Vec<_> = values
+            .into_iter()
+            .map(|value| value.and_then(|value| serde_json::from_slice(&value).ok()))
+            .collect();
+
+        let hits = results.iter().filter(|result| result.is_some()).count();
+        counter!("batch_proxy_cache_hits_total", "endpoint" => TApiEndpoint::NAME, "cache" => "disk")
+            .increment(hits as u64);
+        counter!("batch_proxy_cache_misses_total", "endpoint" => TApiEndpoint::NAME, "cache" => "disk")
+            .increment((results.len() - hits) as u64);
+
+        results
+    }
+
+    /// Stores results of the inputs in the background, together with other pending results.
+    pub fn insert_many(
+        &self,
+        grouping_params: &TApiEndpoint::GroupingParams,
+        inputs: &[TApiEndpoint::DataItem],
+        items: &[TApiEndpoint::ApiResponseItem],
+    ) {
+        let entries: Vec<_> = inputs
+            .iter()
+            .zip(items)
+            .filter_map(|(input, item)| {
+                let value = serde_json::to_vec(item).ok()?;
+                Some((self.key(grouping_params, input), value))
+            })
+            .collect();
+
+        self.store.queue_insert(entries);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::test_utils::{TestApiEndpoint, TestGroupingParams};
+
+    use super::*;
+
+    fn settings(path: &std::path::Path, version: &str) -> DiskCacheSettings {
+        DiskCacheSettings {
+            path: path.to_string_lossy().into_owned(),
+            max_size_bytes: 1024,
+            version: version.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn given_reopened_store_when_version_changed_should_purge_cached_results() {
+        let path = std::env::temp_dir().join(format!("batch_proxy_{}.redb", uuid::Uuid::new_v4()));
+        let params = TestGroupingParams(0);
+
+        let store = Arc::new(DiskCacheStore::open(&settings(&path, "v1")).unwrap());
+        let cache = DiskCache::<TestApiEndpoint>::new(Arc::clone(&store));
+        store
+            .insert(vec![(cache.key(&params, &1), b"10".to_vec())])
+            .unwrap();
+        drop(cache);
+        drop(store);
+
+        let cache = DiskCache::<TestApiEndpoint>::new(Arc::new(
+            DiskCacheStore::open(&settings(&path, "v1")).unwrap(),
+        ));
+        assert_eq!(
+            cache.get_many(&params, &[&1, &2]).await,
+            vec![Some(10), None]
+        );
+        drop(cache);
+
+        let cache = DiskCache::<TestApiEndpoint>::new(Arc::new(
+            DiskCacheStore::open(&settings(&path, "v2")).unwrap(),
+        ));
+        assert_eq!(cache.get_many(&params, &[&1]).await, vec![None]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn given_size_limit_reached_when_inserting_should_evict_oldest_entries() {
+        let path = std::env::temp_dir().join(format!("batch_proxy_{}.redb", uuid::Uuid::new_v4()));
+        let store = DiskCacheStore::open(&settings(&path, "v1")).unwrap();
+        let entry = |key: u8| (vec![key; 32], vec![0; 400]);
+
+        store.insert(vec![entry(1), entry(2)]).unwrap();
+        store.insert(vec![entry(3)]).unwrap();
+
+        let cached = store
+            .get(&[vec![1; 32], vec![2; 32], vec![3; 32]])
+            .unwrap()
+            .iter()
+            .map(Option::is_some)
+            .collect::<Vec<_>>();
+        assert_eq!(cached, vec![false, true, true]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn given_results_inserted_when_writer_finished_should_read_them() {
+        let path = std::env::temp_dir().join(format!("batch_proxy_{}.redb", uuid::Uuid::new_v4()));
+        let params = TestGroupingParams(0);
+        let cache = DiskCache::<TestApiEndpoint>::new(Arc::new(
+            DiskCacheStore::open(&settings(&path, "v1")).unwrap(),
+        ));
+
+        cache.insert_many(&params, &[1, 2], &[10, 20]);
+        cache.insert_many(&params, &[3], &[30]);
+        while cache.store.pending_writes.lock().unwrap().is_writing {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+
+        assert_eq!(
+            cache.get_many(&params, &[&1, &2, &3]).await,
+            vec![Some(10), Some(20), Some(30)]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
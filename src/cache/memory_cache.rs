@@ -0,0 +1,217 @@
+use std::{sync::Mutex, time::Duration};
+
+use lru::LruCache;
+use metrics::{counter, gauge};
+use tokio::time::Instant;
+
+use crate::{
+    api::endpoint::{ApiEndpont, MeasurableInput},
+    settings::MemoryCacheSettings,
+};
+
+type CacheKey<TApiEndpoint> = (
+    <TApiEndpoint as ApiEndpont>::GroupingParams,
+    <TApiEndpoint as ApiEndpont>::DataItem,
+);
+
+struct CacheEntry<TApiEndpoint: ApiEndpont> {
+    item: TApiEndpoint::ApiResponseItem,
+    size_bytes: usize,
+    cached_at: Instant,
+}
+
+struct CacheEntries<TApiEndpoint: ApiEndpont> {
+    lru: LruCache<CacheKey<TApiEndpoint>, CacheEntry<TApiEndpoint>>,
+    size_bytes: usize,
+}
+
+impl<TApiEndpoint: ApiEndpont> CacheEntries<TApiEndpoint> {
+    fn remove(&mut self, key: &CacheKey<TApiEndpoint>) {
+        if let Some(entry) = self.lru.pop(key) {
+            self.size_bytes -= entry.size_bytes;
+        }
+    }
+}
+
+/// Results of single inputs for given grouping parameters, bounded by their approximate size
+/// in memory. Least recently used results are evicted first.
+pub struct MemoryCache<TApiEndpoint: ApiEndpont> {
+    entries: Mutex<CacheEntries<TApiEndpoint>>,
+    max_size_bytes: usize,
+    ttl: Option<Duration>,
+}
+
+impl<TApiEndpoint: ApiEndpont> MemoryCache<TApiEndpoint> {
+    pub fn new(settings: &MemoryCacheSettings) -> Self {
+        Self {
+            entries: Mutex::new(CacheEntries {
+                lru: LruCache::unbounded(),
+                size_bytes: 0,
+            }),
+            max_size_bytes: settings.max_size_bytes,
+            ttl: settings.ttl_ms.map(Duration::from_millis),
+        }
+    }
+
+    /// Cached results of the inputs, `None` for inputs that are not cached or have expired.
+    pub fn get_many(
+        &self,
+        grouping_params: &TApiEndpoint::GroupingParams,
+        inputs: &[TApiEndpoint::DataItem],
+    ) -> Vec<Option<TApiEndpoint::ApiResponseItem>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        let results: Vec<_> = inputs
+            .iter()
+            .map(|input| {
+                let key = (grouping_params.clone(), input.clone());
+                let entry = entries.lru.get(&key)?;
+
+                if self
+                    .ttl
+                    .is_some_and(|ttl| now.duration_since(entry.cached_at) >= ttl)
+                {
+                    entries.remove(&key);
+                    return None;
+                }
+
+                Some(entry.item.clone())
+            })
+            .collect();
+
+        let hits = results.iter().filter(|result| result.is_some()).count();
+        counter!("batch_proxy_cache_hits_total", "endpoint" => TApiEndpoint::NAME, "cache" => "memory")
+            .increment(hits as u64);
+        counter!("batch_proxy_cache_misses_total", "endpoint" => TApiEndpoint::NAME, "cache" => "memory")
+            .increment((results.len() - hits) as u64);
+        gauge!("batch_proxy_cache_size_bytes", "endpoint" => TApiEndpoint::NAME, "cache" => "memory")
+            .set(entries.size_bytes as f64);
+
+        results
+    }
+
+    /// Caches results of the inputs, evicting least recently used results over the size limit.
+    pub fn insert_many(
+        &self,
+        grouping_params: &TApiEndpoint::GroupingParams,
+        inputs: Vec<TApiEndpoint::DataItem>,
+        items: &[TApiEndpoint::ApiResponseItem],
+    ) {
+        let now = Instant::now();
+        // Every entry holds its own copy of the grouping parameters, e.g. the rerank query.
+        let grouping_params_size = serde_json::to_vec(grouping_params).map_or(0, |json| json.len());
+        let mut entries = self.entries.lock().unwrap();
+
+        for (input, item) in inputs.into_iter().zip(items) {
+            let size_bytes = size_of::<CacheKey<TApiEndpoint>>()
+                + grouping_params_size
+                + input.heap_size()
+                + TApiEndpoint::response_item_size(item);
+            if size_bytes > self.max_size_bytes {
+                continue;
+            }
+
+            let key = (grouping_params.clone(), input);
+            entries.remove(&key);
+            entries.lru.put(
+                key,
+                CacheEntry {
+                    item: item.clone(),
+                    size_bytes,
+                    cached_at: now,
+                },
+            );
+            entries.size_bytes += size_bytes;
+
+            while entries.size_bytes > self.max_size_bytes {
+                let Some((_, evicted)) = entries.lru.pop_lru() else {
+                    break;
+                };
+                entries.size_bytes -= evicted.size_bytes;
+            }
+        }
+
+        gauge!("batch_proxy_cache_size_bytes", "endpoint" => TApiEndpoint::NAME, "cache" => "memory")
+            .set(entries.size_bytes as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{
+        api::endpoint::{
+            GroupingParams,
+            embed_endpoint::{EmbedApiEndpoint, EmbedInput, EmbedRequestGroupingParams},
+        },
+        batch::test_utils::{TestApiEndpoint, TestGroupingParams},
+    };
+
+    use super::*;
+
+    fn cache(max_items: usize, ttl_ms: Option<u64>) -> MemoryCache<TestApiEndpoint> {
+        let grouping_params_size = serde_json::to_vec(&TestGroupingParams(0)).unwrap().len();
+        let item_size =
+            size_of::<CacheKey<TestApiEndpoint>>() + grouping_params_size + size_of::<u32>();
+
+        MemoryCache::new(&MemoryCacheSettings {
+            max_size_bytes: max_items * item_size,
+            ttl_ms,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_size_limit_reached_when_inserting_should_evict_least_recently_used() {
+        let cache = cache(2, None);
+        let params = TestGroupingParams(0);
+
+        cache.insert_many(&params, vec![1, 2], &[10, 20]);
+        cache.get_many(&params, &[1]);
+        cache.insert_many(&params, vec![3], &[30]);
+
+        assert_eq!(
+            cache.get_many(&params, &[1, 2, 3]),
+            vec![Some(10), None, Some(30)]
+        );
+        assert_eq!(cache.get_many(&TestGroupingParams(1), &[1]), vec![None]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_ttl_passed_when_getting_should_miss() {
+        let cache = cache(2, Some(100));
+        let params = TestGroupingParams(0);
+        cache.insert_many(&params, vec![1], &[10]);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get_many(&params, &[1]), vec![Some(10)]);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get_many(&params, &[1]), vec![None]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_token_ids_over_size_limit_when_inserting_should_not_cache_them() {
+        let cache = MemoryCache::<EmbedApiEndpoint>::new(&MemoryCacheSettings {
+            max_size_bytes: 1024,
+            ttl_ms: None,
+        });
+        let (_, params) = EmbedRequestGroupingParams::decompose_api_request(
+            serde_json::from_value(json!({ "inputs": [] })).unwrap(),
+        );
+        let short = EmbedInput::Ids(vec![1; 8]);
+        let long = EmbedInput::Ids(vec![1; 512]);
+
+        cache.insert_many(
+            &params,
+            vec![short.clone(), long.clone()],
+            &[vec![0.0], vec![0.0]],
+        );
+
+        assert_eq!(
+            cache.get_many(&params, &[short, long]),
+            vec![Some(vec![0.0]), None]
+        );
+    }
+}
@@ -0,0 +1,2 @@
+pub mod disk_cache;
+pub mod memory_cache;
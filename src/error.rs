@@ -0,0 +1,147 @@
+use actix_web::{HttpResponse, ResponseError, http::StatusCode};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::api::client::ApiClientError;
+
+pub type ProxyResult<T> = Result<T, ProxyError>;
+
+/// Error that is sent back to every client of a failed request.
+#[derive(Error, Debug, Clone)]
+pub enum ProxyError {
+    #[error("{message}")]
+    Upstream {
+        status: u16,
+        message: String,
+        error_type: Option<String>,
+    },
+
+    #[error("Inference API request timed out.")]
+    UpstreamTimeout,
+
+    #[error("Request deadline was exceeded before the response was ready.")]
+    DeadlineExceeded,
+
+    #[error("Inference API is unavailable.")]
+    UpstreamUnavailable,
+
+    #[error("{0}")]
+    Overloaded(String),
+
+    #[error("Inference API returned an invalid response. {0}")]
+    Integrity(String),
+
+    #[error("{0}")]
+    Internal(String),
+}
+
+/// Error body in the same format as the upstream API uses.
+#[derive(Debug, Serialize)]
+struct ErrorResponse<'a> {
+    error: String,
+    error_type: &'a str,
+}
+
+impl ProxyError {
+    /// Whether the upstream rejected the request because of its inputs,
+    /// as opposed to being overloaded or failing on its own.
+    pub fn is_input_error(&self) -> bool {
+        match self {
+            ProxyError::Upstream {
+                status, error_type, ..
+            } => {
+                matches!(status, 413 | 422)
+                    || matches!(error_type.as_deref(), Some("validation" | "tokenizer"))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn error_type(&self) -> &str {
+        match self {
+            ProxyError::Upstream { error_type, .. } => error_type.as_deref().unwrap_or("backend"),
+            ProxyError::UpstreamTimeout | ProxyError::DeadlineExceeded => "timeout",
+            ProxyError::UpstreamUnavailable => "unhealthy",
+            ProxyError::Overloaded(_) => "overloaded",
+            ProxyError::Integrity(_) => "integrity",
+            ProxyError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl ResponseError for ProxyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ProxyError::Upstream { status, .. } => {
+                StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_GATEWAY)
+            }
+            ProxyError::UpstreamTimeout | ProxyError::DeadlineExceeded => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            ProxyError::UpstreamUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+            ProxyError::Integrity(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            error: self.to_string(),
+            error_type: self.error_type(),
+        })
+    }
+}
+
+impl From<ApiClientError> for ProxyError {
+    fn from(error: ApiClientError) -> Self {
+        match error {
+            ApiClientError::Upstream {
+                status,
+                message,
+                error_type,
+                ..
+            } => ProxyError::Upstream {
+                status: status.as_u16(),
+                message,
+                error_type,
+            },
+            ApiClientError::Timeout(_) => ProxyError::UpstreamTimeout,
+            ApiClientError::Connect(_) | ApiClientError::CircuitOpen => {
+                ProxyError::UpstreamUnavailable
+            }
+            ApiClientError::Request(_) | ApiClientError::Other(_) => {
+                ProxyError::Internal("API call failed. Please try again".to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode as UpstreamStatusCode;
+
+    use super::*;
+
+    #[test]
+    fn given_upstream_validation_error_when_converted_should_keep_status_and_body() {
+        let error = ProxyError::from(ApiClientError::Upstream {
+            status: UpstreamStatusCode::UNPROCESSABLE_ENTITY,
+            message: "Input is too long".to_string(),
+            error_type: Some("validation".to_string()),
+            retry_after: None,
+        });
+
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error.error_type(), "validation");
+        assert_eq!(error.to_string(), "Input is too long");
+    }
+
+    #[test]
+    fn given_timeout_when_mapped_should_return_gateway_timeout() {
+        assert_eq!(
+            ProxyError::UpstreamTimeout.status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+}
@@ -1,53 +1,330 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use actix_web::{App, HttpServer, post, web};
+use actix_web::{
+    App, HttpRequest, HttpServer,
+    error::{ErrorInternalServerError, ErrorNotFound},
+    get, post, web,
+};
 use api::{
     api_data_provider::ApiDataProvider,
-    client::reqwest_api_client::ReqwestApiClient,
-    endpoint::embed_endpoint::{EmbedApiEndpoint, EmbedApiRequest},
+    client::{
+        circuit_breaker_api_client::CircuitBreakerApiClient,
+        load_balanced_api_client::{LoadBalancedApiClient, Upstream},
+        reqwest_api_client::ReqwestApiClient,
+    },
+    endpoint::{
+        embed_endpoint::{EmbedApiEndpoint, EmbedApiRequest},
+        embed_sparse_endpoint::{EmbedSparseApiEndpoint, EmbedSparseApiRequest},
+        predict_endpoint::{PredictApiEndpoint, PredictApiRequest, PredictApiRequestInputs},
+        rerank_endpoint::{self, RerankApiEndpoint, RerankApiRequest},
+    },
+    openai::{OpenAiEmbeddingsRequest, OpenAiEmbeddingsResponse, OpenAiError},
+    retry::RetryPolicy,
+};
+use batch::{
+    batch_manager::{self, BatchManagerHandle},
+    token_counter::{CharacterTokenCounter, TokenCounter, WhitespaceTokenCounter},
 };
-use batch::batch_manager::{self, BatchManagerHandle};
-use settings::Settings;
+use cache::disk_cache::DiskCacheStore;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use request::RequestOptions;
+use settings::{Settings, TokenEstimate};
+use tokio::sync::Semaphore;
 
 mod api;
 mod batch;
+mod cache;
+mod error;
 mod request;
 mod settings;
 
 #[post("/embed")]
 async fn embed(
     batch_manager: web::Data<BatchManagerHandle<EmbedApiEndpoint>>,
+    http_request: HttpRequest,
+    settings: web::Data<Settings>,
     req: web::Json<EmbedApiRequest>,
 ) -> actix_web::Result<String> {
     let result = batch_manager
-        .call_api(req.into_inner())
-        .await
-        .map_err(|e: anyhow::Error| actix_web::error::ErrorInternalServerError(e))?;
+        .call_api(
+            req.into_inner(),
+            RequestOptions::from_http_request(&http_request, &settings.priority)?,
+        )
+        .await?;
 
     let json = serde_json::to_string(&result)?;
 
     Ok(json)
 }
 
+#[post("/v1/embeddings")]
+async fn openai_embeddings(
+    batch_manager: web::Data<BatchManagerHandle<EmbedApiEndpoint>>,
+    http_request: HttpRequest,
+    settings: web::Data<Settings>,
+    req: actix_web::Result<web::Json<OpenAiEmbeddingsRequest>>,
+) -> Result<String, OpenAiError> {
+    let req = req?.into_inner();
+    let model = req.model.clone();
+    let encoding_format = req.encoding_format;
+    let estimated_prompt_tokens = req.estimated_prompt_tokens();
+
+    let (result, token_counts) = batch_manager
+        .call_api_counting_tokens(
+            req.into_embed_request(),
+            RequestOptions::from_http_request(&http_request, &settings.priority)?,
+        )
+        .await?;
+    let prompt_tokens = token_counts.map_or(estimated_prompt_tokens, |token_counts| {
+        token_counts.iter().sum()
+    });
+
+    let response = OpenAiEmbeddingsResponse::new(result, model, encoding_format, prompt_tokens);
+    let json = serde_json::to_string(&response)?;
+
+    Ok(json)
+}
+
+#[post("/embed_sparse")]
+async fn embed_sparse(
+    batch_manager: web::Data<BatchManagerHandle<EmbedSparseApiEndpoint>>,
+    http_request: HttpRequest,
+    settings: web::Data<Settings>,
+    req: web::Json<EmbedSparseApiRequest>,
+) -> actix_web::Result<String> {
+    let result = batch_manager
+        .call_api(
+            req.into_inner(),
+            RequestOptions::from_http_request(&http_request, &settings.priority)?,
+        )
+        .await?;
+
+    let json = serde_json::to_string(&result)?;
+
+    Ok(json)
+}
+
+#[post("/predict")]
+async fn predict(
+    batch_manager: web::Data<BatchManagerHandle<PredictApiEndpoint>>,
+    http_request: HttpRequest,
+    settings: web::Data<Settings>,
+    req: web::Json<PredictApiRequest>,
+) -> actix_web::Result<String> {
+    let req = req.into_inner();
+    let is_single_input = matches!(req.inputs, PredictApiRequestInputs::Single(_));
+
+    let mut result = batch_manager
+        .call_api(
+            req,
+            RequestOptions::from_http_request(&http_request, &settings.priority)?,
+        )
+        .await?;
+
+    let json = if is_single_input {
+        serde_json::to_string(&result.pop().unwrap_or_default())?
+    } else {
+        serde_json::to_string(&result)?
+    };
+
+    Ok(json)
+}
+
+#[post("/rerank")]
+async fn rerank(
+    batch_manager: web::Data<BatchManagerHandle<RerankApiEndpoint>>,
+    http_request: HttpRequest,
+    settings: web::Data<Settings>,
+    req: web::Json<RerankApiRequest>,
+) -> actix_web::Result<String> {
+    let result = batch_manager
+        .call_api(
+            req.into_inner(),
+            RequestOptions::from_http_request(&http_request, &settings.priority)?,
+        )
+        .await?;
+
+    let json = serde_json::to_string(&rerank_endpoint::into_client_response(result))?;
+
+    Ok(json)
+}
+
+type AppDataProvider =
+    ApiDataProvider<CircuitBreakerApiClient<LoadBalancedApiClient<ReqwestApiClient>>>;
+
+#[get("/admin/upstreams")]
+async fn upstreams_status(data_provider: web::Data<AppDataProvider>) -> actix_web::Result<String> {
+    let json = serde_json::to_string(&data_provider.api_client.inner().upstreams_status())?;
+
+    Ok(json)
+}
+
+#[post("/admin/cache/purge")]
+async fn purge_cache(
+    disk_cache_store: Option<web::Data<DiskCacheStore>>,
+) -> actix_web::Result<&'static str> {
+    let disk_cache_store =
+        disk_cache_store.ok_or_else(|| ErrorNotFound("Disk cache is not enabled."))?;
+
+    web::block(move || disk_cache_store.purge())
+        .await?
+        .map_err(ErrorInternalServerError)?;
+
+    Ok("Disk cache was purged.")
+}
+
+#[get("/metrics")]
+async fn metrics(prometheus_handle: web::Data<PrometheusHandle>) -> String {
+    prometheus_handle.render()
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
+    let prometheus_handle = PrometheusBuilder::new().install_recorder().unwrap();
+    let upkeep_handle = prometheus_handle.clone();
+    tokio::spawn(async move {
+        let mut upkeep_interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            upkeep_interval.tick().await;
+            upkeep_handle.run_upkeep();
+        }
+    });
+    let prometheus_data = web::Data::new(prometheus_handle);
+
     let settings = web::Data::new(Settings::new().unwrap());
     let target_port = settings.api.target_port;
-    let api_client = ReqwestApiClient::new(&settings.inference_api.target_url).unwrap();
+    let upstreams = settings
+        .inference_api
+        .upstreams()
+        .into_iter()
+        .map(|upstream| {
+            let client = ReqwestApiClient::new(&upstream.url, &settings.inference_api.http_client)?;
+            Ok(Upstream::new(
+                upstream.url,
+                client,
+                upstream.weight.unwrap_or(1),
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .unwrap();
+    let api_client = LoadBalancedApiClient::new(
+        upstreams,
+        settings.inference_api.load_balancing,
+        settings.inference_api.health_check.clone(),
+    );
+    api_client.start_health_checks();
+    let api_client =
+        CircuitBreakerApiClient::new(api_client, settings.inference_api.circuit_breaker.clone());
 
-    let data_provider = Arc::new(ApiDataProvider { api_client });
+    let data_provider = Arc::new(ApiDataProvider {
+        availability: api_client.subscribe(),
+        api_client,
+        retry_policy: RetryPolicy::new(settings.inference_api.retry.clone()),
+    });
+    let data_provider_data = web::Data::from(Arc::clone(&data_provider));
+    let in_flight_batches = Arc::new(Semaphore::new(settings.batch.max_in_flight_batches));
+    let token_counter: Option<Arc<dyn TokenCounter>> =
+        settings
+            .batch
+            .token_budget
+            .as_ref()
+            .map(|token_budget| -> Arc<dyn TokenCounter> {
+                match token_budget.estimate {
+                    TokenEstimate::Characters => Arc::new(CharacterTokenCounter),
+                    TokenEstimate::Whitespace => Arc::new(WhitespaceTokenCounter),
+                    TokenEstimate::Tokenizer => Arc::clone(&data_provider) as Arc<dyn TokenCounter>,
+                }
+            });
 
-    let batch_managerv2 = batch_manager::start(Arc::clone(&data_provider), settings.batch.clone());
+    let disk_cache_store = settings
+        .cache
+        .disk
+        .as_ref()
+        .map(|disk_cache| DiskCacheStore::open(disk_cache).map(Arc::new))
+        .transpose()
+        .map_err(std::io::Error::other)?;
+    let disk_cache_store_data = disk_cache_store.clone().map(web::Data::from);
+
+    let batch_managerv2 = batch_manager::start::<EmbedApiEndpoint>(
+        Arc::clone(&data_provider),
+        settings.batch.clone(),
+        Arc::clone(&in_flight_batches),
+        token_counter.clone(),
+        &settings.cache,
+        disk_cache_store.clone(),
+    );
     let batch_manager_data = web::Data::new(batch_managerv2);
 
-    HttpServer::new(move || {
+    let embed_sparse_batch_manager = batch_manager::start::<EmbedSparseApiEndpoint>(
+        Arc::clone(&data_provider),
+        settings.batch.clone(),
+        Arc::clone(&in_flight_batches),
+        token_counter.clone(),
+        &settings.cache,
+        disk_cache_store.clone(),
+    );
+    let embed_sparse_batch_manager_data = web::Data::new(embed_sparse_batch_manager);
+
+    let predict_batch_manager = batch_manager::start::<PredictApiEndpoint>(
+        Arc::clone(&data_provider),
+        settings.batch.clone(),
+        Arc::clone(&in_flight_batches),
+        token_counter.clone(),
+        &settings.cache,
+        disk_cache_store.clone(),
+    );
+    let predict_batch_manager_data = web::Data::new(predict_batch_manager);
+
+    let rerank_batch_manager = batch_manager::start::<RerankApiEndpoint>(
+        Arc::clone(&data_provider),
+        settings.batch.clone(),
+        Arc::clone(&in_flight_batches),
+        token_counter.clone(),
+        &settings.cache,
+        disk_cache_store.clone(),
+    );
+    let rerank_batch_manager_data = web::Data::new(rerank_batch_manager);
+
+    let admin_settings = settings.admin.clone();
+
+    let api_server = HttpServer::new(move || {
         App::new()
             .app_data(batch_manager_data.clone())
+            .app_data(embed_sparse_batch_manager_data.clone())
+            .app_data(predict_batch_manager_data.clone())
+            .app_data(rerank_batch_manager_data.clone())
             .app_data(settings.clone())
+            .app_data(prometheus_data.clone())
+            .service(metrics)
             .service(embed)
+            .service(openai_embeddings)
+            .service(embed_sparse)
+            .service(predict)
+            .service(rerank)
     })
     .bind(("0.0.0.0", target_port))?
-    .run()
-    .await
+    .run();
+
+    let Some(admin_settings) = admin_settings else {
+        return api_server.await;
+    };
+
+    let admin_server = HttpServer::new(move || {
+        let app = match &disk_cache_store_data {
+            Some(disk_cache_store_data) => App::new().app_data(disk_cache_store_data.clone()),
+            None => App::new(),
+        };
+
+        app.app_data(data_provider_data.clone())
+            .service(upstreams_status)
+            .service(purge_cache)
+    })
+    .workers(1)
+    .bind((admin_settings.host.as_str(), admin_settings.port))?
+    .run();
+
+    tokio::try_join!(api_server, admin_server)?;
+
+    Ok(())
 }
@@ -1,8 +1,98 @@
+use std::time::Duration;
+
+use actix_web::{HttpRequest, error::ErrorBadRequest, http::header::AUTHORIZATION};
 use log::error;
-use tokio::sync::oneshot;
+use serde::Deserialize;
+use tokio::{sync::oneshot, time::Instant};
 use uuid::Uuid;
 
-use crate::api::endpoint::ApiEndpont;
+use crate::{
+    api::endpoint::ApiEndpont,
+    error::{ProxyError, ProxyResult},
+    settings::PrioritySettings,
+};
+
+/// Header with the number of milliseconds the client is willing to wait for the response.
+pub const REQUEST_TIMEOUT_HEADER: &str = "X-Request-Timeout-Ms";
+/// Header with the request priority, `high` or `low`.
+pub const REQUEST_PRIORITY_HEADER: &str = "X-Request-Priority";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Latency-sensitive requests, flushed after a short wait and batched first.
+    High,
+    /// Bulk requests, fill the capacity left over by high priority requests.
+    Low,
+}
+
+/// Client preferences that affect how the request is batched.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestOptions {
+    /// Point in time after which the client is no longer interested in the result.
+    pub deadline: Option<Instant>,
+    pub priority: Priority,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            deadline: None,
+            priority: Priority::High,
+        }
+    }
+}
+
+impl RequestOptions {
+    /// Reads the options from request headers. Priority configured for the API key takes
+    /// precedence over the priority header, which is not checked in that case.
+    pub fn from_http_request(
+        req: &HttpRequest,
+        priority_settings: &PrioritySettings,
+    ) -> actix_web::Result<Self> {
+        let headers = req.headers();
+
+        let deadline = headers
+            .get(REQUEST_TIMEOUT_HEADER)
+            .map(|timeout| {
+                timeout
+                    .to_str()
+                    .ok()
+                    .and_then(|timeout| timeout.parse().ok())
+                    .ok_or_else(|| {
+                        ErrorBadRequest(format!(
+                            "{REQUEST_TIMEOUT_HEADER} must be a number of milliseconds."
+                        ))
+                    })
+            })
+            .transpose()?
+            .map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms));
+
+        let api_key_priority = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|api_key| priority_settings.api_keys.get(api_key.trim()))
+            .copied();
+        let priority = match api_key_priority {
+            Some(priority) => priority,
+            None => match headers.get(REQUEST_PRIORITY_HEADER) {
+                Some(priority) => match priority.to_str() {
+                    Ok("high") => Priority::High,
+                    Ok("low") => Priority::Low,
+                    _ => {
+                        return Err(ErrorBadRequest(format!(
+                            "{REQUEST_PRIORITY_HEADER} must be `high` or `low`."
+                        )));
+                    }
+                },
+                None => priority_settings.default,
+            },
+        };
+
+        Ok(Self { deadline, priority })
+    }
+}
 
 pub struct RequestClient<TApiEndpoint>
 where
@@ -10,14 +100,18 @@ where
 {
     pub handle: RequestHandle<TApiEndpoint::ApiResponseItem>,
     pub data: Vec<TApiEndpoint::DataItem>,
+    pub priority: Priority,
+    /// Token counts of the inputs, empty if batches are not limited by tokens.
+    pub token_counts: Vec<usize>,
 }
 
 impl<TApiEndpoint: ApiEndpont> RequestClient<TApiEndpoint> {
     pub fn new(
         data: Vec<TApiEndpoint::DataItem>,
         client_id: Uuid,
+        options: RequestOptions,
     ) -> (
-        oneshot::Receiver<anyhow::Result<Vec<TApiEndpoint::ApiResponseItem>>>,
+        oneshot::Receiver<ProxyResult<Vec<TApiEndpoint::ApiResponseItem>>>,
         Self,
     ) {
         let (sender, receiver) = oneshot::channel();
@@ -25,20 +119,108 @@ impl<TApiEndpoint: ApiEndpont> RequestClient<TApiEndpoint> {
             handle: RequestHandle {
                 client_id,
                 reply_handle: sender,
+                deadline: options.deadline,
             },
             data,
+            priority: options.priority,
+            token_counts: Vec::new(),
         };
 
         (receiver, client)
     }
+
+    pub fn token_count(&self) -> usize {
+        self.token_counts.iter().sum()
+    }
+
+    /// Splits the request into requests of at most `max_inputs` inputs and, if given, `max_tokens`
+    /// tokens, that can go into different batches. An input over the token limit gets a request
+    /// of its own. Their results are joined in order and sent to the original client.
+    pub fn into_chunks(self, max_inputs: usize, max_tokens: Option<usize>) -> Vec<Self> {
+        let RequestClient {
+            handle,
+            data,
+            priority,
+            token_counts,
+        } = self;
+        let options = RequestOptions {
+            deadline: handle.deadline,
+            priority,
+        };
+
+        let mut receivers = Vec::new();
+        let mut chunks: Vec<Self> = Vec::new();
+        let mut token_counts = token_counts.into_iter();
+        for item in data {
+            let item_tokens = token_counts.next().unwrap_or(0);
+            let fits_last_chunk = chunks.last().is_some_and(|chunk| {
+                chunk.data.len() < max_inputs.max(1)
+                    && max_tokens
+                        .is_none_or(|max_tokens| chunk.token_count() + item_tokens <= max_tokens)
+            });
+
+            if !fits_last_chunk {
+                let (receiver, chunk) = RequestClient::new(Vec::new(), handle.client_id, options);
+                receivers.push(receiver);
+                chunks.push(chunk);
+            }
+
+            let chunk = chunks.last_mut().expect("A chunk was just added.");
+            chunk.data.push(item);
+            if max_tokens.is_some() {
+                chunk.token_counts.push(item_tokens);
+            }
+        }
+
+        tokio::spawn(handle.join_chunks(receivers));
+
+        chunks
+    }
 }
 
 pub struct RequestHandle<O> {
-    pub reply_handle: oneshot::Sender<anyhow::Result<Vec<O>>>,
+    pub reply_handle: oneshot::Sender<ProxyResult<Vec<O>>>,
     pub client_id: Uuid,
+    /// Point in time after which the client is no longer interested in the result.
+    pub deadline: Option<Instant>,
 }
 
 impl<O> RequestHandle<O> {
+    /// Whether the client has gone away and no longer waits for the result.
+    pub fn is_closed(&self) -> bool {
+        self.reply_handle.is_closed()
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+
+    /// Replies with the results of all chunks in order, or with the first error.
+    async fn join_chunks(mut self, receivers: Vec<oneshot::Receiver<ProxyResult<Vec<O>>>>) {
+        let chunk_results = async {
+            let mut results = Vec::new();
+            for receiver in receivers {
+                let chunk_result = receiver.await.map_err(|_| {
+                    ProxyError::Internal("Request was dropped before completion.".to_string())
+                })?;
+                results.extend(chunk_result?);
+            }
+
+            Ok(results)
+        };
+
+        // Dropping the chunk receivers lets the worker discard chunks that are still queued.
+        let result = tokio::select! {
+            result = chunk_results => result,
+            _ = self.reply_handle.closed() => return,
+        };
+
+        match result {
+            Ok(result) => self.reply_with_result(result),
+            Err(err) => self.reply_with_error(err),
+        }
+    }
+
     pub fn reply_with_result(self, result: Vec<O>) {
         self.reply_handle.send(Ok(result)).unwrap_or_else(|_| {
             error!(
@@ -48,7 +230,7 @@ impl<O> RequestHandle<O> {
         });
     }
 
-    pub fn reply_with_error(self, error: anyhow::Error) {
+    pub fn reply_with_error(self, error: ProxyError) {
         self.reply_handle.send(Err(error)).unwrap_or_else(|_| {
             error!(
                 "Could not send response to client, receiver has dropped. [ClientId = {0}]",
@@ -57,3 +239,45 @@ impl<O> RequestHandle<O> {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn given_api_key_with_priority_when_header_set_should_use_api_key_priority() {
+        let settings = PrioritySettings {
+            default: Priority::High,
+            api_keys: HashMap::from([("reindexing".to_string(), Priority::Low)]),
+        };
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer reindexing"))
+            .insert_header((REQUEST_PRIORITY_HEADER, "high"))
+            .to_http_request();
+
+        let options = RequestOptions::from_http_request(&req, &settings).unwrap();
+
+        assert_eq!(options.priority, Priority::Low);
+        assert!(options.deadline.is_none());
+    }
+
+    #[test]
+    fn given_api_key_with_priority_when_header_invalid_should_ignore_header() {
+        let settings = PrioritySettings {
+            default: Priority::High,
+            api_keys: HashMap::from([("reindexing".to_string(), Priority::Low)]),
+        };
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer reindexing"))
+            .insert_header((REQUEST_PRIORITY_HEADER, "urgent"))
+            .to_http_request();
+
+        let options = RequestOptions::from_http_request(&req, &settings).unwrap();
+
+        assert_eq!(options.priority, Priority::Low);
+    }
+}
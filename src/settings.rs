@@ -1,12 +1,128 @@
 use config::{Config, ConfigError, Environment, File};
 use log::info;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::request::Priority;
 
 #[derive(Deserialize, Debug, Clone)]
 #[allow(unused)]
 pub struct BatchSettings {
     pub max_batch_size: usize,
     pub max_waiting_time_ms: u64,
+    pub low_priority_max_waiting_time_ms: u64,
+    /// Low priority requests that waited this long are batched before high priority ones.
+    pub low_priority_starvation_ms: u64,
+    pub max_workers: usize,
+    pub worker_idle_timeout_ms: u64,
+    pub max_in_flight_batches_per_worker: usize,
+    pub max_in_flight_batches: usize,
+    /// Adjusts the window and the batch size to the load, the settings above are upper bounds then.
+    #[serde(default)]
+    pub adaptive: Option<AdaptiveBatchingSettings>,
+    /// Limits batches by the token count of their inputs in addition to `max_batch_size`.
+    #[serde(default)]
+    pub token_budget: Option<TokenBudgetSettings>,
+    /// Groups inputs of similar length into the same batch to reduce padding upstream.
+    #[serde(default)]
+    pub length_sorting: Option<LengthSortingSettings>,
+    /// Rules that change settings of workers for matching grouping parameters.
+    #[serde(default)]
+    pub overrides: Vec<BatchOverrideSettings>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenEstimate {
+    /// A token per four characters.
+    Characters,
+    /// A token per whitespace separated word.
+    Whitespace,
+    /// Exact counts from the upstream `/tokenize` endpoint.
+    Tokenizer,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct TokenBudgetSettings {
+    /// Should not exceed `max_batch_tokens` of the text inference API.
+    pub max_batch_tokens: usize,
+    pub estimate: TokenEstimate,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct LengthSortingSettings {
+    /// Requests whose longest inputs differ by more than this factor go into different batches.
+    pub max_length_ratio: f64,
+    /// Flushes a request may be held back for before it joins a batch regardless of its length.
+    pub max_held_flushes: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct AdaptiveBatchingSettings {
+    pub min_batch_size: usize,
+    pub min_waiting_time_ms: u64,
+    /// Weight of the latest observation in the moving averages, between 0 and 1.
+    pub smoothing_factor: f64,
+}
+
+/// Worker settings for grouping parameters that match the rule, first matching rule is applied.
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct BatchOverrideSettings {
+    /// Endpoint name the rule applies to, every endpoint if not set.
+    pub endpoint: Option<String>,
+    /// Grouping parameter values that must all be equal, e.g. `{ prompt_name = "query" }`.
+    #[serde(rename = "match", default)]
+    pub matches: HashMap<String, serde_json::Value>,
+    pub max_batch_size: Option<usize>,
+    pub max_waiting_time_ms: Option<u64>,
+    pub low_priority_max_waiting_time_ms: Option<u64>,
+    pub low_priority_starvation_ms: Option<u64>,
+    pub max_in_flight_batches_per_worker: Option<usize>,
+}
+
+impl BatchOverrideSettings {
+    fn matches(&self, endpoint: &str, grouping_params: &serde_json::Value) -> bool {
+        self.endpoint.as_deref().is_none_or(|e| e == endpoint)
+            && self
+                .matches
+                .iter()
+                .all(|(field, value)| grouping_params.get(field) == Some(value))
+    }
+}
+
+impl BatchSettings {
+    /// Settings for a worker of the given endpoint and grouping parameters,
+    /// with the first matching override applied.
+    pub fn for_group(&self, endpoint: &str, grouping_params: &impl Serialize) -> BatchSettings {
+        let grouping_params = serde_json::to_value(grouping_params).unwrap_or_default();
+        let mut settings = self.clone();
+
+        if let Some(rule) = self
+            .overrides
+            .iter()
+            .find(|rule| rule.matches(endpoint, &grouping_params))
+        {
+            settings.max_batch_size = rule.max_batch_size.unwrap_or(settings.max_batch_size);
+            settings.max_waiting_time_ms = rule
+                .max_waiting_time_ms
+                .unwrap_or(settings.max_waiting_time_ms);
+            settings.low_priority_max_waiting_time_ms = rule
+                .low_priority_max_waiting_time_ms
+                .unwrap_or(settings.low_priority_max_waiting_time_ms);
+            settings.low_priority_starvation_ms = rule
+                .low_priority_starvation_ms
+                .unwrap_or(settings.low_priority_starvation_ms);
+            settings.max_in_flight_batches_per_worker = rule
+                .max_in_flight_batches_per_worker
+                .unwrap_or(settings.max_in_flight_batches_per_worker);
+        }
+
+        settings
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -15,18 +131,151 @@ pub struct ApiSettings {
     pub target_port: u16,
 }
 
+/// Listener of the `/admin` routes, kept apart from the public API so that it can be bound
+/// to a private interface.
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct AdminSettings {
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct RetrySettings {
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancingStrategy {
+    RoundRobin,
+    LeastOutstanding,
+    Weighted,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct UpstreamSettings {
+    pub url: String,
+    pub weight: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct HealthCheckSettings {
+    pub interval_ms: u64,
+    pub unhealthy_threshold: u32,
+    pub ejection_threshold: u32,
+    pub ejection_cooldown_ms: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct HttpClientSettings {
+    pub connect_timeout_ms: u64,
+    pub request_timeout_ms: u64,
+    /// Added to the request timeout for every item in the batch.
+    pub request_timeout_per_item_ms: u64,
+    pub pool_idle_timeout_ms: u64,
+    pub pool_max_idle_per_host: usize,
+    pub http2_prior_knowledge: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct CircuitBreakerSettings {
+    pub window_size: usize,
+    pub minimum_calls: usize,
+    pub failure_rate_threshold: f64,
+    pub open_duration_ms: u64,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[allow(unused)]
 pub struct InferenceApiSettings {
-    pub target_url: String,
+    pub target_url: Option<String>,
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamSettings>,
+    pub load_balancing: LoadBalancingStrategy,
+    pub http_client: HttpClientSettings,
+    pub retry: RetrySettings,
+    pub health_check: HealthCheckSettings,
+    pub circuit_breaker: CircuitBreakerSettings,
+}
+
+impl InferenceApiSettings {
+    /// Configured upstreams, `target_url` is used as the only upstream if the list is empty.
+    pub fn upstreams(&self) -> Vec<UpstreamSettings> {
+        if !self.upstreams.is_empty() {
+            return self.upstreams.clone();
+        }
+
+        self.target_url
+            .iter()
+            .map(|url| UpstreamSettings {
+                url: url.clone(),
+                weight: None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct PrioritySettings {
+    /// Priority of requests without the priority header.
+    pub default: Priority,
+    /// Priorities assigned to bearer API keys, override the priority header.
+    #[serde(default)]
+    pub api_keys: HashMap<String, Priority>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct MemoryCacheSettings {
+    /// Approximate memory used by cached inputs and results, least recently used ones are evicted.
+    pub max_size_bytes: usize,
+    /// Cached results are not used once they are older than this, kept until evicted if not set.
+    pub ttl_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(unused)]
+pub struct DiskCacheSettings {
+    /// Database file, created if it does not exist.
+    pub path: String,
+    /// Oldest results are evicted once cached keys and results take more space than this.
+    pub max_size_bytes: u64,
+    /// Identity of the served model, e.g. its name and revision. Results cached for another
+    /// version are purged on start.
+    pub version: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[allow(unused)]
+pub struct CacheSettings {
+    /// Caches results per input in memory, disabled if not set.
+    pub memory: Option<MemoryCacheSettings>,
+    /// Caches results per input on disk, so that they survive restarts. Checked after the
+    /// memory cache, disabled if not set.
+    pub disk: Option<DiskCacheSettings>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 #[allow(unused)]
 pub struct Settings {
     pub api: ApiSettings,
+    /// Admin routes are disabled if not set.
+    #[serde(default)]
+    pub admin: Option<AdminSettings>,
     pub inference_api: InferenceApiSettings,
     pub batch: BatchSettings,
+    pub priority: PrioritySettings,
+    #[serde(default)]
+    pub cache: CacheSettings,
 }
 
 impl Settings {
@@ -44,3 +293,60 @@ impl Settings {
         Ok(settings)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use config::FileFormat;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn given_matching_override_when_worker_settings_requested_should_apply_it() {
+        let batch_settings: BatchSettings = Config::builder()
+            .add_source(File::from_str(
+                r#"
+                max_batch_size = 32
+                max_waiting_time_ms = 8
+                low_priority_max_waiting_time_ms = 50
+                low_priority_starvation_ms = 1000
+                max_workers = 1024
+                worker_idle_timeout_ms = 60000
+                max_in_flight_batches_per_worker = 4
+                max_in_flight_batches = 64
+
+                [[overrides]]
+                endpoint = "embed"
+                match = { prompt_name = "query", dimensions = 256 }
+                max_waiting_time_ms = 2
+                "#,
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        let query = json!({ "prompt_name": "query", "dimensions": 256 });
+        let document = json!({ "prompt_name": "document", "dimensions": 256 });
+
+        assert_eq!(
+            batch_settings
+                .for_group("embed", &query)
+                .max_waiting_time_ms,
+            2
+        );
+        assert_eq!(
+            batch_settings
+                .for_group("embed_sparse", &query)
+                .max_waiting_time_ms,
+            8
+        );
+        assert_eq!(
+            batch_settings
+                .for_group("embed", &document)
+                .max_waiting_time_ms,
+            8
+        );
+    }
+}